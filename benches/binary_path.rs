@@ -0,0 +1,57 @@
+#![feature(test)]
+extern crate test;
+extern crate clcache;
+
+use clcache::cache::CacheBackend;
+use clcache::cache::binary::Binary;
+use clcache::cache::volatile::Volatile;
+use std::collections::HashMap;
+use test::Bencher;
+
+const BINARY_SIZE: usize = 8 * 1024 * 1024;
+const DEVICES: usize = 4;
+
+fn populated_backend() -> (Volatile, Vec<String>) {
+	let mut backend = Volatile::new();
+	let mut keys = Vec::new();
+
+	for i in 0..DEVICES {
+		let key = format!("device_{}", i);
+		backend.put(&key, &Binary::from(vec![i as u8; BINARY_SIZE])).unwrap();
+		keys.push(key);
+	}
+
+	(backend, keys)
+}
+
+// Mimics the previous path: the binary was copied out of the backend, again into
+// the map indexed by device and again into the final vector of binaries
+#[bench]
+fn copied_binaries_on_hit(b: &mut Bencher) {
+	let (backend, keys) = populated_backend();
+
+	b.iter(|| {
+		let mut binaries_hash: HashMap<usize, Vec<u8>> = HashMap::new();
+		for (idx, key) in keys.iter().enumerate() {
+			binaries_hash.insert(idx, backend.get(key).unwrap().to_vec());
+		}
+
+		let final_binaries: Vec<Vec<u8>> = (0..DEVICES).map(|idx| binaries_hash[&idx].clone()).collect();
+		test::black_box(final_binaries)
+	});
+}
+
+#[bench]
+fn shared_binaries_on_hit(b: &mut Bencher) {
+	let (backend, keys) = populated_backend();
+
+	b.iter(|| {
+		let mut binaries_hash: HashMap<usize, Binary> = HashMap::new();
+		for (idx, key) in keys.iter().enumerate() {
+			binaries_hash.insert(idx, backend.get(key).unwrap());
+		}
+
+		let final_binaries: Vec<Binary> = (0..DEVICES).map(|idx| binaries_hash[&idx].clone()).collect();
+		test::black_box(final_binaries)
+	});
+}
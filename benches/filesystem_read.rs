@@ -0,0 +1,47 @@
+#![feature(test)]
+extern crate test;
+extern crate clcache;
+
+use clcache::cache::CacheBackend;
+use clcache::cache::binary::Binary;
+use clcache::cache::disk::FileSystemCache;
+use std::env;
+use test::Bencher;
+
+const BINARY_SIZE: usize = 4 * 1024 * 1024;
+const KERNELS: usize = 32;
+
+fn populated_cache(name: &str) -> (FileSystemCache, Vec<String>) {
+	let path = env::temp_dir().join(name).to_str().unwrap().to_string();
+	let mut cache = FileSystemCache::new(path).unwrap();
+	let mut keys = Vec::new();
+
+	for i in 0..KERNELS {
+		let key = format!("kernel_{}", i);
+		cache.put(&key, &Binary::from(vec![i as u8; BINARY_SIZE])).unwrap();
+		keys.push(key);
+	}
+
+	(cache, keys)
+}
+
+// Loads all the kernels, as an application would do at startup
+fn load_all(cache: &FileSystemCache, keys: &Vec<String>) -> Vec<Binary> {
+	keys.iter().map(|key| cache.get(key).unwrap()).collect()
+}
+
+#[bench]
+fn buffered_startup_load(b: &mut Bencher) {
+	let (mut cache, keys) = populated_cache("clcache_bench_buffered");
+	cache.set_mmap(false);
+
+	b.iter(|| test::black_box(load_all(&cache, &keys)));
+}
+
+#[bench]
+fn mapped_startup_load(b: &mut Bencher) {
+	let (mut cache, keys) = populated_cache("clcache_bench_mapped");
+	cache.set_mmap(true);
+
+	b.iter(|| test::black_box(load_all(&cache, &keys)));
+}
@@ -1,5 +1,162 @@
-extern unsigned int cl_cache_create_fs(char* path);
-extern cl_program cl_cache_get(unsigned int cache_id, char* source, unsigned char num_devices, void* devices, void *context);
-extern cl_program cl_cache_get_with_options(unsigned int cache_id, char* source, unsigned char num_devices, void* devices, void *context, char* options);
-extern cl_program cl_cache_get_with_tag(unsigned int cache_id, char* tag, unsigned char num_devices, void* devices, void *context);
-extern cl_program cl_cache_put_with_tag(unsigned int cache_id, char* tag, unsigned char num_devices, void* devices, void *program);
\ No newline at end of file
+#ifndef CL_CACHE_H
+#define CL_CACHE_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __APPLE__
+#include <OpenCL/opencl.h>
+#else
+#include <CL/cl.h>
+#endif
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Version of the API described by this header. Compare it with cl_cache_api_version()
+ * to detect a library that does not match the header */
+#define CL_CACHE_API_VERSION 8
+
+extern unsigned int cl_cache_api_version(void);
+
+/* Cache creation. All of them return the id of the cache, or -1 on error. The CLCACHE_DISABLE,
+ * CLCACHE_READONLY, CLCACHE_EXTRA_OPTIONS, CLCACHE_LOG and CLCACHE_SIGNING_KEY environment
+ * variables apply to all of them */
+
+#define CL_CACHE_CONFIG_VERSION 1
+
+#define CL_CACHE_BACKEND_FILESYSTEM 0
+#define CL_CACHE_BACKEND_MEMORY 1
+#define CL_CACHE_BACKEND_PACK 2
+#define CL_CACHE_BACKEND_SQLITE 3
+
+typedef struct {
+	/* Must be CL_CACHE_CONFIG_VERSION */
+	uint32_t version;
+	/* One of the CL_CACHE_BACKEND_* values */
+	uint32_t backend;
+	/* Directory or file of the storage. Ignored by the memory backend. File system caches
+	 * use CLCACHE_DIR or the XDG cache directory when it's NULL */
+	const char* path;
+	/* Non zero to only serve hits from an existing storage */
+	int32_t read_only;
+	/* Maximum size in bytes of the binaries, or 0 for no limit. Not supported by pack caches */
+	uint64_t max_size;
+	/* Non zero to use the sharded layout on file system caches */
+	int32_t sharded;
+} cl_cache_config;
+
+extern int cl_cache_create(const cl_cache_config* config);
+extern int cl_cache_create_fs(const char* path);
+/* File system cache on CLCACHE_DIR (or the XDG cache directory), limited by CLCACHE_MAX_SIZE */
+extern int cl_cache_create_from_env(void);
+extern int cl_cache_create_memory(uint64_t max_size);
+
+/* Binaries embedded on the executable, like the ones of the headers generated by the embed
+ * command. The cache is read-only and the binaries are not copied, so they have to outlive it */
+typedef struct {
+	const char* key;
+	/* Device and platform version the binary was built for. Only informative */
+	const char* device;
+	const char* platform_version;
+	const unsigned char* binary;
+	size_t size;
+} cl_cache_embedded_entry;
+
+extern int cl_cache_create_embedded(const cl_cache_embedded_entry* entries, size_t count);
+
+#define CL_CACHE_CALLBACK_OK 0
+#define CL_CACHE_CALLBACK_NOT_FOUND 1
+
+typedef int (*cl_cache_get_callback)(void* user_data, const char* key, const unsigned char** payload, size_t* size);
+typedef void (*cl_cache_release_callback)(void* user_data, const unsigned char* payload, size_t size);
+typedef int (*cl_cache_put_callback)(void* user_data, const char* key, const unsigned char* payload, size_t size);
+typedef int (*cl_cache_delete_callback)(void* user_data, const char* key);
+
+extern int cl_cache_create_custom(cl_cache_get_callback get, cl_cache_put_callback put, cl_cache_delete_callback del, cl_cache_release_callback release, void* user_data);
+
+/* Destroys the cache. Returns 1 on success */
+extern int cl_cache_destroy(int cache_id);
+/* Destroys all the caches of the current thread */
+extern void cl_cache_shutdown(void);
+
+/* Programs. The get functions return NULL on error */
+
+extern cl_program cl_cache_get(int cache_id, const char* source, unsigned char num_devices, const cl_device_id* devices, cl_context context);
+extern cl_program cl_cache_get_with_options(int cache_id, const char* source, unsigned char num_devices, const cl_device_id* devices, cl_context context, const char* options);
+extern cl_program cl_cache_get_with_tag(int cache_id, const char* tag, unsigned char num_devices, const cl_device_id* devices, cl_context context);
+extern cl_program cl_cache_get_with_tag_and_options(int cache_id, const char* tag, unsigned char num_devices, const cl_device_id* devices, cl_context context, const char* options);
+
+/* Same as cl_cache_get_with_options, with the source provided like on clCreateProgramWithSource.
+ * Without lengths, or with a length of 0, the strings are NUL terminated. options can be NULL */
+extern cl_program cl_cache_get_with_sources(int cache_id, cl_uint count, const char** strings, const size_t* lengths, cl_uint num_devices, const cl_device_id* devices, cl_context context, const char* options);
+
+/* Stores the binaries of a built program. Return 1 on success */
+extern int cl_cache_put_with_tag(int cache_id, const char* tag, unsigned char num_devices, const cl_device_id* devices, cl_program program);
+extern int cl_cache_put_with_tag_and_options(int cache_id, const char* tag, unsigned char num_devices, const cl_device_id* devices, cl_program program, const char* options);
+
+/* Same signature as clBuildProgram, using the cache selected with cl_cache_set_build_cache
+ * (-1 to build without cache). Without substitutes, the program is always built (a hit does not
+ * save the compilation), and its binaries are stored on a miss.
+ * With cl_cache_set_program_substitutes(1), a hit does not build the source program: the program
+ * built from the cached binaries has to be used on its place (cl_cache_program returns it), and the
+ * program has to be retained and released with cl_cache_retain_program and cl_cache_release_program */
+typedef void (CL_CALLBACK *cl_cache_build_notify)(cl_program program, void* user_data);
+
+extern int cl_cache_set_build_cache(int cache_id);
+extern int cl_cache_set_program_substitutes(int enabled);
+extern cl_int cl_cache_build_program(cl_program program, cl_uint num_devices, const cl_device_id* device_list, const char* options, cl_cache_build_notify pfn_notify, void* user_data);
+extern cl_program cl_cache_program(cl_program program);
+extern cl_int cl_cache_retain_program(cl_program program);
+extern cl_int cl_cache_release_program(cl_program program);
+
+/* Removes stored binaries. Return 1 on success, also when there was nothing to remove */
+extern int cl_cache_invalidate(int cache_id, const char* source, unsigned char num_devices, const cl_device_id* devices, const char* options);
+extern int cl_cache_invalidate_tag(int cache_id, const char* tag, unsigned char num_devices, const cl_device_id* devices, const char* options);
+
+/* Statistics. Hits and misses are counted per device */
+
+typedef struct {
+	uint64_t hits;
+	uint64_t misses;
+	uint64_t compilations;
+	uint64_t stores;
+	uint64_t invalidations;
+	/* Entries rejected because of an invalid signature */
+	uint64_t rejections;
+} cl_cache_stats;
+
+extern int cl_cache_get_stats(int cache_id, cl_cache_stats* stats);
+
+/* Signs the entries with HMAC-SHA256 and the size bytes of secret. Entries without a valid
+ * signature are rejected and built again. A NULL secret disables the signatures. Returns 1 on success */
+extern int cl_cache_set_signing_key(int cache_id, const unsigned char* secret, size_t size);
+
+/* Releases the programs kept for repeated requests of the same context. Each one retains its
+ * context, so call it before releasing a context that should be destroyed. Returns 1 on success */
+extern int cl_cache_clear_programs(int cache_id);
+
+/* Errors */
+
+#define CL_CACHE_SUCCESS 0
+#define CL_CACHE_INVALID_CACHE 1
+#define CL_CACHE_INVALID_ARGUMENT 2
+#define CL_CACHE_BUILD_ERROR 3
+#define CL_CACHE_OPENCL_ERROR 4
+#define CL_CACHE_NOT_ALL_BINARIES_LOADED 5
+#define CL_CACHE_NEED_BINARY_PROGRAM 6
+#define CL_CACHE_BACKEND_ERROR 7
+#define CL_CACHE_READ_ONLY 8
+#define CL_CACHE_IO_ERROR 9
+#define CL_CACHE_COULD_NOT_CREATE_CACHE 10
+
+extern int cl_cache_last_error(void);
+extern const char* cl_cache_last_error_message(void);
+extern const char* cl_cache_last_build_log(cl_device_id device);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
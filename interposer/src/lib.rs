@@ -0,0 +1,220 @@
+//! Library to be loaded with `LD_PRELOAD` on applications that can not be modified.
+//!
+//! It interposes `clBuildProgram`, which is replaced by `cl_cache_build_program` with substitutes.
+//! As the application keeps the handle of the source program, the program built from the cached
+//! binaries is used on its place by the functions that need a built program (kernel creation
+//! and program queries). The references of the programs are counted from `clCreateProgramWithSource`
+//! on `clRetainProgram` and `clReleaseProgram`, so the substitute is released along with the
+//! source program.
+//!
+//! The cache is configured with the `CLCACHE_*` environment variables (see `clcache::config::EnvConfig`).
+//! With `CLCACHE_DISABLE`, all the calls are forwarded as they are.
+#![allow(non_snake_case)]
+extern crate clcache;
+extern crate opencl;
+extern crate libc;
+#[macro_use]
+extern crate lazy_static;
+#[macro_use]
+extern crate log;
+
+mod real;
+
+use clcache::{BuildNotify, cl_cache_build_program, cl_cache_create_from_env, cl_cache_set_build_cache, cl_cache_set_program_substitutes};
+use clcache::build;
+use clcache::config::EnvConfig;
+use opencl::cl::*;
+use real::*;
+use std::cell::Cell;
+use std::ptr;
+
+const PROGRAM_REFERENCE_COUNT: cl_uint = 0x1160;
+const PROGRAM_SOURCE: cl_uint = 0x1164;
+// CL_INVALID_OPERATION, returned when the real function was not found
+const MISSING_FUNCTION: cl_int = -59;
+
+lazy_static! {
+	static ref REAL: RealFunctions = RealFunctions::load();
+	static ref CONFIG: EnvConfig = EnvConfig::from_env();
+}
+
+/// Returns the real function, or returns `$missing` from the caller if it was not found
+macro_rules! real {
+	($function:ident, $missing:expr) => {
+		match REAL.$function {
+			Some(function) => function,
+			None => return $missing,
+		}
+	};
+}
+
+// The library calls OpenCL with the same symbols that are interposed, so the calls done
+// while the cache is working are forwarded as they are
+thread_local!(static BYPASS: Cell<bool> = Cell::new(false));
+// Caches are owned by the thread that creates them, so each thread opens its own
+thread_local!(static CACHE_READY: Cell<Option<bool>> = Cell::new(None));
+
+fn is_bypassed() -> bool {
+	BYPASS.with(|bypass| bypass.get())
+}
+
+fn bypass<F, T>(f: F) -> T where F: FnOnce() -> T {
+	BYPASS.with(|bypass| bypass.set(true));
+	let result = f();
+	BYPASS.with(|bypass| bypass.set(false));
+
+	result
+}
+
+/// Opens the cache of the current thread, if it was not opened yet
+fn cache_ready() -> bool {
+	if let Some(ready) = CACHE_READY.with(|ready| ready.get()) {
+		return ready;
+	}
+
+	CONFIG.init_log();
+
+	let ready = if CONFIG.disabled {
+		info!("clcache-preload: Disabled by CLCACHE_DISABLE");
+		false
+	} else {
+		let cache_id = cl_cache_create_from_env();
+		if cache_id == -1 {
+			warn!("clcache-preload: Could not open the cache on {:?}", CONFIG.directory);
+		}
+
+		// Every use of the programs goes through the interposer, so the hits can use substitutes
+		cache_id != -1 && cl_cache_set_build_cache(cache_id) == 1 && cl_cache_set_program_substitutes(1) == 1
+	};
+
+	CACHE_READY.with(|cache_ready| cache_ready.set(Some(ready)));
+
+	ready
+}
+
+/// Returns the program built from binaries if the given one was replaced by it
+fn substitute(program: cl_program) -> cl_program {
+	if is_bypassed() {
+		return program;
+	}
+
+	build::resolve(program)
+}
+
+#[no_mangle]
+pub extern "C" fn clCreateProgramWithSource(
+	context: cl_context,
+	count: cl_uint,
+	strings: *const *const libc::c_char,
+	lengths: *const libc::size_t,
+	errcode_ret: *mut cl_int
+) -> cl_program
+{
+	let create_program_with_source = real!(create_program_with_source, missing_program(errcode_ret));
+	let program = create_program_with_source(context, count, strings, lengths, errcode_ret);
+
+	// The references are counted from the creation, so the ones retained before the build are not missed
+	if !program.is_null() && !is_bypassed() && cache_ready() {
+		build::track(program);
+	}
+
+	program
+}
+
+#[no_mangle]
+pub extern "C" fn clBuildProgram(
+	program: cl_program,
+	num_devices: cl_uint,
+	device_list: *const cl_device_id,
+	options: *const libc::c_char,
+	pfn_notify: BuildNotify,
+	user_data: *mut libc::c_void
+) -> cl_int
+{
+	if is_bypassed() || !cache_ready() {
+		let build_program = real!(build_program, MISSING_FUNCTION);
+		return build_program(program, num_devices, device_list, options, pfn_notify, user_data);
+	}
+
+	// The callback is called out of the bypass, so it can use the replaced program
+	let status = bypass(|| cl_cache_build_program(program, num_devices, device_list, options, None, ptr::null_mut()));
+	if let Some(notify) = pfn_notify {
+		notify(program, user_data);
+	}
+
+	status
+}
+
+#[no_mangle]
+pub extern "C" fn clCreateKernel(program: cl_program, kernel_name: *const libc::c_char, errcode_ret: *mut cl_int) -> cl_kernel {
+	let create_kernel = real!(create_kernel, missing_program(errcode_ret) as cl_kernel);
+	create_kernel(substitute(program), kernel_name, errcode_ret)
+}
+
+#[no_mangle]
+pub extern "C" fn clCreateKernelsInProgram(program: cl_program, num_kernels: cl_uint, kernels: *mut cl_kernel, num_kernels_ret: *mut cl_uint) -> cl_int {
+	let create_kernels_in_program = real!(create_kernels_in_program, MISSING_FUNCTION);
+	create_kernels_in_program(substitute(program), num_kernels, kernels, num_kernels_ret)
+}
+
+#[no_mangle]
+pub extern "C" fn clGetProgramInfo(
+	program: cl_program,
+	param_name: cl_uint,
+	param_value_size: libc::size_t,
+	param_value: *mut libc::c_void,
+	param_value_size_ret: *mut libc::size_t
+) -> cl_int
+{
+	// The source and the reference count are the ones of the program known by the application
+	let program = match param_name {
+		PROGRAM_SOURCE | PROGRAM_REFERENCE_COUNT => program,
+		_ => substitute(program),
+	};
+
+	let get_program_info = real!(get_program_info, MISSING_FUNCTION);
+	get_program_info(program, param_name, param_value_size, param_value, param_value_size_ret)
+}
+
+#[no_mangle]
+pub extern "C" fn clGetProgramBuildInfo(
+	program: cl_program,
+	device: cl_device_id,
+	param_name: cl_uint,
+	param_value_size: libc::size_t,
+	param_value: *mut libc::c_void,
+	param_value_size_ret: *mut libc::size_t
+) -> cl_int
+{
+	let get_program_build_info = real!(get_program_build_info, MISSING_FUNCTION);
+	get_program_build_info(substitute(program), device, param_name, param_value_size, param_value, param_value_size_ret)
+}
+
+#[no_mangle]
+pub extern "C" fn clRetainProgram(program: cl_program) -> cl_int {
+	if is_bypassed() {
+		let retain_program = real!(retain_program, MISSING_FUNCTION);
+		return retain_program(program);
+	}
+
+	bypass(|| build::retain_program(program))
+}
+
+#[no_mangle]
+pub extern "C" fn clReleaseProgram(program: cl_program) -> cl_int {
+	if is_bypassed() {
+		let release_program = real!(release_program, MISSING_FUNCTION);
+		return release_program(program);
+	}
+
+	bypass(|| build::release_program(program))
+}
+
+/// Reports a missing function to the callers that expect a new object
+fn missing_program(errcode_ret: *mut cl_int) -> cl_program {
+	if !errcode_ret.is_null() {
+		unsafe { *errcode_ret = MISSING_FUNCTION };
+	}
+
+	ptr::null_mut()
+}
@@ -0,0 +1,57 @@
+use clcache::BuildNotify;
+use libc;
+use opencl::cl::*;
+use std::ffi::CStr;
+use std::mem;
+
+pub type CreateProgramWithSource = extern "C" fn(cl_context, cl_uint, *const *const libc::c_char, *const libc::size_t, *mut cl_int) -> cl_program;
+pub type BuildProgram = extern "C" fn(cl_program, cl_uint, *const cl_device_id, *const libc::c_char, BuildNotify, *mut libc::c_void) -> cl_int;
+pub type CreateKernel = extern "C" fn(cl_program, *const libc::c_char, *mut cl_int) -> cl_kernel;
+pub type CreateKernelsInProgram = extern "C" fn(cl_program, cl_uint, *mut cl_kernel, *mut cl_uint) -> cl_int;
+pub type GetProgramInfo = extern "C" fn(cl_program, cl_uint, libc::size_t, *mut libc::c_void, *mut libc::size_t) -> cl_int;
+pub type GetProgramBuildInfo = extern "C" fn(cl_program, cl_device_id, cl_uint, libc::size_t, *mut libc::c_void, *mut libc::size_t) -> cl_int;
+pub type RetainProgram = extern "C" fn(cl_program) -> cl_int;
+pub type ReleaseProgram = extern "C" fn(cl_program) -> cl_int;
+
+/// Functions of the OpenCL library loaded after the interposer. The ones that are not found are
+/// `None`, and the calls to them fail with an error status
+pub struct RealFunctions {
+	pub create_program_with_source: Option<CreateProgramWithSource>,
+	pub build_program: Option<BuildProgram>,
+	pub create_kernel: Option<CreateKernel>,
+	pub create_kernels_in_program: Option<CreateKernelsInProgram>,
+	pub get_program_info: Option<GetProgramInfo>,
+	pub get_program_build_info: Option<GetProgramBuildInfo>,
+	pub retain_program: Option<RetainProgram>,
+	pub release_program: Option<ReleaseProgram>,
+}
+
+impl RealFunctions {
+	pub fn load() -> RealFunctions {
+		unsafe {
+			RealFunctions {
+				create_program_with_source: next_symbol(b"clCreateProgramWithSource\0").map(|symbol| mem::transmute(symbol)),
+				build_program: next_symbol(b"clBuildProgram\0").map(|symbol| mem::transmute(symbol)),
+				create_kernel: next_symbol(b"clCreateKernel\0").map(|symbol| mem::transmute(symbol)),
+				create_kernels_in_program: next_symbol(b"clCreateKernelsInProgram\0").map(|symbol| mem::transmute(symbol)),
+				get_program_info: next_symbol(b"clGetProgramInfo\0").map(|symbol| mem::transmute(symbol)),
+				get_program_build_info: next_symbol(b"clGetProgramBuildInfo\0").map(|symbol| mem::transmute(symbol)),
+				retain_program: next_symbol(b"clRetainProgram\0").map(|symbol| mem::transmute(symbol)),
+				release_program: next_symbol(b"clReleaseProgram\0").map(|symbol| mem::transmute(symbol)),
+			}
+		}
+	}
+}
+
+unsafe fn next_symbol(name: &'static [u8]) -> Option<*mut libc::c_void> {
+	let symbol = libc::dlsym(libc::RTLD_NEXT, name.as_ptr() as *const libc::c_char);
+
+	// A panic can not unwind through the C callers, so the missing functions are only reported
+	if symbol.is_null() {
+		let name = CStr::from_ptr(name.as_ptr() as *const libc::c_char);
+		error!("clcache-preload: Could not find {:?} on the OpenCL library", name);
+		return None;
+	}
+
+	Some(symbol)
+}
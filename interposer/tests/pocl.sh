@@ -0,0 +1,32 @@
+#!/bin/sh
+# Runs an unmodified OpenCL application with the interposer. It needs an ICD with a CPU
+# device, like POCL (OCL_ICD_VENDORS can point to the directory with its .icd file).
+set -e
+
+cd "$(dirname "$0")/.."
+cargo build
+
+LIBRARY="$(pwd)/target/debug/libclcache_preload.so"
+WORKDIR="$(mktemp -d)"
+trap 'rm -rf "$WORKDIR"' EXIT
+
+cc -o "$WORKDIR/vector_add" tests/vector_add.c -lOpenCL
+
+# First run compiles the program and stores the binary, second one loads it
+for run in miss hit; do
+	CLCACHE_DIR="$WORKDIR/cache" LD_PRELOAD="$LIBRARY" "$WORKDIR/vector_add"
+done
+
+ENTRIES=$(find "$WORKDIR/cache" -name '*.clbin' | wc -l)
+if [ "$ENTRIES" -ne 1 ]; then
+	echo "Expected one cache entry, found $ENTRIES"
+	exit 1
+fi
+
+CLCACHE_DISABLE=1 CLCACHE_DIR="$WORKDIR/disabled" LD_PRELOAD="$LIBRARY" "$WORKDIR/vector_add"
+if [ -d "$WORKDIR/disabled" ]; then
+	echo "The cache should not be created when it's disabled"
+	exit 1
+fi
+
+echo "Interposer test passed"
@@ -0,0 +1,71 @@
+/* Unmodified OpenCL application used to check the interposer */
+#include <stdio.h>
+#include <string.h>
+
+#ifdef __APPLE__
+#include <OpenCL/opencl.h>
+#else
+#include <CL/cl.h>
+#endif
+
+static const char* header = "#define FACTOR 2\n";
+static const char* body =
+	"__kernel void vector_add(__global const int* a, __global const int* b, __global int* c) {\n"
+	"  int i = get_global_id(0);\n"
+	"  c[i] = a[i] + b[i] * FACTOR;\n"
+	"}\n";
+
+int main(void) {
+	cl_platform_id platform;
+	cl_device_id device;
+	cl_int error;
+	int a[4] = {1, 2, 3, 4}, b[4] = {4, 3, 2, 1}, c[4];
+
+	clGetPlatformIDs(1, &platform, NULL);
+	clGetDeviceIDs(platform, CL_DEVICE_TYPE_ALL, 1, &device, NULL);
+	cl_context context = clCreateContext(NULL, 1, &device, NULL, NULL, &error);
+	cl_command_queue queue = clCreateCommandQueue(context, device, 0, &error);
+
+	const char* strings[2] = {header, body};
+	cl_program program = clCreateProgramWithSource(context, 2, strings, NULL, &error);
+	if (clBuildProgram(program, 1, &device, "", NULL, NULL) != CL_SUCCESS) {
+		fprintf(stderr, "Build failed\n");
+		return 1;
+	}
+
+	cl_kernel kernel = clCreateKernel(program, "vector_add", &error);
+	if (error != CL_SUCCESS) {
+		fprintf(stderr, "Kernel creation failed: %d\n", error);
+		return 1;
+	}
+
+	cl_mem a_mem = clCreateBuffer(context, CL_MEM_READ_ONLY | CL_MEM_COPY_HOST_PTR, sizeof(a), a, &error);
+	cl_mem b_mem = clCreateBuffer(context, CL_MEM_READ_ONLY | CL_MEM_COPY_HOST_PTR, sizeof(b), b, &error);
+	cl_mem c_mem = clCreateBuffer(context, CL_MEM_WRITE_ONLY, sizeof(c), NULL, &error);
+
+	clSetKernelArg(kernel, 0, sizeof(cl_mem), &a_mem);
+	clSetKernelArg(kernel, 1, sizeof(cl_mem), &b_mem);
+	clSetKernelArg(kernel, 2, sizeof(cl_mem), &c_mem);
+
+	size_t global_size = 4;
+	clEnqueueNDRangeKernel(queue, kernel, 1, NULL, &global_size, NULL, 0, NULL, NULL);
+	clEnqueueReadBuffer(queue, c_mem, CL_TRUE, 0, sizeof(c), c, 0, NULL, NULL);
+
+	for (int i = 0; i < 4; i++) {
+		if (c[i] != a[i] + b[i] * 2) {
+			fprintf(stderr, "Wrong result on %d: %d\n", i, c[i]);
+			return 1;
+		}
+	}
+
+	clReleaseMemObject(a_mem);
+	clReleaseMemObject(b_mem);
+	clReleaseMemObject(c_mem);
+	clReleaseKernel(kernel);
+	clReleaseProgram(program);
+	clReleaseCommandQueue(queue);
+	clReleaseContext(context);
+
+	printf("OK\n");
+	return 0;
+}
@@ -0,0 +1,109 @@
+extern crate clcache;
+#[macro_use] extern crate clap;
+extern crate ansi_term;
+extern crate env_logger;
+
+use clap::{App, ArgMatches};
+use clcache::cache::bundle::{self, Manifest, Selection};
+use clcache::cache::disk::FileSystemCache;
+use clcache::cache::signed::Signer;
+use clcache::config::EnvConfig;
+use std::io::{self, Write};
+use std::process;
+
+use ansi_term::Colour::*;
+
+pub fn main() {
+	env_logger::init().unwrap();
+	let yaml = load_yaml!("bundle_clap.yml");
+	let matches = App::from_yaml(yaml).get_matches();
+	let config = EnvConfig::from_env();
+
+	let directory = match matches.value_of("cache_directory") {
+		Some(directory) => directory.to_string(),
+		None => match config.directory {
+			Some(ref directory) => directory.clone(),
+			None => fail("No cache directory: Use -c or set CLCACHE_DIR".to_string()),
+		},
+	};
+
+	let result = match matches.subcommand() {
+		("export", Some(arguments)) => export(&directory, arguments),
+		("import", Some(arguments)) => import(&directory, arguments, &config),
+		("list", Some(arguments)) => bundle::read_manifest(arguments.value_of("bundle").unwrap()).map(|x| show(&x)).map_err(|x| x.to_string()),
+		_ => Err("Use one of the export, import or list commands (see --help)".to_string()),
+	};
+
+	if let Err(error) = result {
+		fail(error);
+	}
+}
+
+fn export(directory: &str, arguments: &ArgMatches) -> Result<(), String> {
+	let cache = match FileSystemCache::read_only(directory.to_string()) {
+		Some(cache) => cache,
+		None => return Err(format!("Could not open the cache on {}", directory)),
+	};
+
+	let mut selections = Vec::new();
+	if let Some(tag) = arguments.value_of("tag") {
+		selections.push(Selection::Tag(tag.to_string()));
+	}
+	if let Some(device) = arguments.value_of("device") {
+		selections.push(Selection::Device(device.to_string()));
+	}
+	if let Some(platform) = arguments.value_of("platform") {
+		selections.push(Selection::Platform(platform.to_string()));
+	}
+
+	let path = arguments.value_of("bundle").unwrap();
+	let manifest = try!{bundle::export(&cache, &selections, path).map_err(|x| x.to_string())};
+	println!("Exported {} entries to {}", Green.bold().paint(&manifest.entries.len().to_string()), path);
+
+	Ok(())
+}
+
+fn import(directory: &str, arguments: &ArgMatches, config: &EnvConfig) -> Result<(), String> {
+	let signer = match (arguments.is_present("verify_signatures"), config.signing_key.as_ref()) {
+		(false, _) => None,
+		(true, Some(key)) => Some(Signer::new(key.as_bytes())),
+		(true, None) => return Err("Signatures can not be verified without CLCACHE_SIGNING_KEY".to_string()),
+	};
+
+	let mut cache = match FileSystemCache::new(directory.to_string()) {
+		Some(cache) => cache,
+		None => return Err(format!("Could not open the cache on {}", directory)),
+	};
+
+	let path = arguments.value_of("bundle").unwrap();
+	let manifest = try!{bundle::import(path, &mut cache, signer.as_ref()).map_err(|x| x.to_string())};
+	println!("Imported {} entries from {}", Green.bold().paint(&manifest.entries.len().to_string()), path);
+
+	Ok(())
+}
+
+fn show(manifest: &Manifest) {
+	println!("Bundle created at {} with {} entries", manifest.created_at, manifest.entries.len());
+
+	for entry in manifest.entries.iter() {
+		println!("{} ({} bytes{})", Cyan.bold().paint(&entry.key), entry.size, if entry.signed { ", signed" } else { "" });
+
+		if let Some(ref tag) = entry.tag {
+			println!("    tag: {}", tag);
+		}
+
+		match entry.metadata {
+			None => println!("    {}", Yellow.paint("Stored without metadata")),
+			Some(ref metadata) => {
+				println!("    device: {}", metadata.device_name);
+				println!("    platform: {}", metadata.platform_version);
+				println!("    options: {}", metadata.options);
+			},
+		}
+	}
+}
+
+fn fail(message: String) -> ! {
+	let _ = writeln!(io::stderr(), "{} {}", Red.bold().paint("Error:"), message);
+	process::exit(1);
+}
@@ -0,0 +1,83 @@
+extern crate clcache;
+#[macro_use] extern crate clap;
+extern crate ansi_term;
+extern crate env_logger;
+
+use clap::{App, ArgMatches};
+use clcache::cache::CacheBackend;
+use clcache::cache::binary::Binary;
+use clcache::cache::bundle::tag_of;
+use clcache::cache::compressed::decompress;
+use clcache::cache::disk::FileSystemCache;
+use clcache::cache::embedded::{generate_c, generate_rust};
+use clcache::config::EnvConfig;
+use std::fs::File;
+use std::io::{self, Write};
+use std::process;
+
+use ansi_term::Colour::*;
+
+pub fn main() {
+	env_logger::init().unwrap();
+	let yaml = load_yaml!("embed_clap.yml");
+	let matches = App::from_yaml(yaml).get_matches();
+
+	if let Err(error) = run(&matches) {
+		let _ = writeln!(io::stderr(), "{} {}", Red.bold().paint("Error:"), error);
+		process::exit(1);
+	}
+}
+
+fn run(matches: &ArgMatches) -> Result<(), String> {
+	let directory = match matches.value_of("cache_directory") {
+		Some(directory) => directory.to_string(),
+		None => try!{EnvConfig::from_env().directory.ok_or("No cache directory: Use -c or set CLCACHE_DIR".to_string())},
+	};
+
+	let cache = try!{FileSystemCache::read_only(directory.clone()).ok_or(format!("Could not open the cache on {}", directory))};
+	let entries = try!{select_entries(&cache, matches)};
+	if entries.is_empty() {
+		return Err("No entry matches the given tags and keys".to_string());
+	}
+
+	if let Some(path) = matches.value_of("rust") {
+		try!{write_file(path, &generate_rust(&entries))};
+		println!("Embedded {} entries on {}", Green.bold().paint(&entries.len().to_string()), path);
+	}
+
+	if let Some(path) = matches.value_of("header") {
+		let name = matches.value_of("name").unwrap_or("clcache");
+		try!{write_file(path, &generate_c(&entries, name))};
+		println!("Embedded {} entries on {}", Green.bold().paint(&entries.len().to_string()), path);
+	}
+
+	Ok(())
+}
+
+fn select_entries(cache: &FileSystemCache, matches: &ArgMatches) -> Result<Vec<(String, Binary)>, String> {
+	let tags: Vec<&str> = matches.values_of("tag").map(|x| x.collect()).unwrap_or(Vec::new());
+	let mut keys: Vec<String> = matches.values_of("key").map(|x| x.map(|key| key.to_string()).collect()).unwrap_or(Vec::new());
+
+	if !tags.is_empty() {
+		let stored = try!{cache.keys().map_err(|x| format!("Could not list the entries of the cache: {:?}", x))};
+		keys.extend(stored.into_iter().filter(|key| tag_of(key).map(|tag| tags.contains(&tag.as_str())).unwrap_or(false)));
+	}
+
+	keys.sort();
+	keys.dedup();
+
+	let mut entries = Vec::new();
+	for key in keys {
+		// Compressed entries are embedded decompressed, as the embedded backend serves them as they are
+		match cache.get(&key).and_then(decompress) {
+			Ok(binary) => entries.push((key, binary)),
+			Err(error) => return Err(format!("Could not read the entry {}: {:?}", key, error)),
+		}
+	}
+
+	Ok(entries)
+}
+
+fn write_file(path: &str, contents: &str) -> Result<(), String> {
+	File::create(path).and_then(|mut file| file.write_all(contents.as_bytes())).map_err(|x| format!("Could not write {}: {}", path, x))
+}
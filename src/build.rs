@@ -0,0 +1,176 @@
+use cache::{Cache, CacheError};
+use cl::device::Device;
+use cl::program::Program;
+use opencl::cl::*;
+use opencl::cl::ll;
+use std::collections::HashMap;
+use std::mem;
+use std::rc::Rc;
+use std::sync::Mutex;
+
+/// Program built from source by `build_program`, or created by the interposer
+struct SourceProgram {
+	/// References of the program owned by the application. The reference count reported by
+	/// OpenCL can not be used to know when the program is destroyed, so they are counted here
+	references: u32,
+	/// Program built from the cached binaries, used on place of the source program
+	built: Option<usize>,
+}
+
+lazy_static! {
+	// Programs whose references are tracked, indexed by their id. The ids are stored as
+	// integers, as raw pointers can not be shared between threads
+	static ref PROGRAMS: Mutex<HashMap<usize, SourceProgram>> = Mutex::new(HashMap::new());
+}
+
+/// Returns the program that has to be used on place of `program`: the one built from the
+/// cached binaries if `program` was built with substitutes and there was a hit, or `program`
+/// itself otherwise
+pub fn resolve(program: cl_program) -> cl_program {
+	match PROGRAMS.lock().unwrap().get(&(program as usize)) {
+		Some(&SourceProgram { built: Some(built), .. }) => built as cl_program,
+		_ => program,
+	}
+}
+
+/// Starts counting the references of a program that was just created, so its substitute is
+/// released along with it. The application owns a single reference of a new program
+pub fn track(program: cl_program) {
+	PROGRAMS.lock().unwrap().insert(program as usize, SourceProgram { references: 1, built: None });
+}
+
+/// Builds a program created from source like `clBuildProgram`, but using the cache.
+///
+/// Without `substitutes`, `program` is always built, so it can be used with any OpenCL call,
+/// and its binaries are stored if they were not on the cache. With `substitutes`, a hit does
+/// not build `program`: the program built from the binaries is registered on its place, and
+/// the caller has to use `resolve` wherever a built program is needed
+pub fn build_program(cache: &mut Cache, program: &Program, devices: &Vec<Rc<Device>>, options: &str, substitutes: bool) -> Result<(), CacheError> {
+	// The cache appends its extra options by itself, but they are also needed to build on a miss
+	let build_options = cache.build_options(options);
+
+	// Programs created from binaries are built as they are
+	let source = match program.get_source() {
+		Err(_) => return build(&program, &devices, &build_options),
+		Ok(source) => source,
+	};
+
+	let cached = if substitutes {
+		let context = try!{program.get_context()};
+
+		match cache.get_cached_with_options(&source, &devices, &context, &options) {
+			Ok(built) => {
+				substitute(program.get_id(), built);
+				return Ok(());
+			},
+			Err(CacheError::NotAllBinariesLoaded(_)) => false,
+			Err(error) => {
+				warn!("Could not load the program from the cache: {}", error);
+				false
+			},
+		}
+	} else {
+		cache.contains_with_options(&source, &devices, &options).unwrap_or(false)
+	};
+
+	remove_substitute(program.get_id());
+	try!{build(&program, &devices, &build_options)};
+
+	if cached {
+		return Ok(());
+	}
+
+	// The program was built with the extra options, so they are part of the stored options
+	match cache.put_with_options(&source, &devices, &program, &build_options) {
+		Ok(_) => (),
+		Err(CacheError::ReadOnly) => info!("Read-only cache: Skipping store for devices: {:?}", devices),
+		Err(error) => warn!("Could not store the program on the cache: {}", error),
+	}
+
+	Ok(())
+}
+
+fn build(program: &Program, devices: &Vec<Rc<Device>>, options: &str) -> Result<(), CacheError> {
+	let build_result = if options.len() > 0 {
+		program.build_with_options(&devices, &options)
+	} else {
+		program.build(&devices)
+	};
+
+	if build_result.is_err() {
+		let mut logs = HashMap::new();
+		for device in devices {
+			logs.insert(device.clone(), program.get_log(&device).unwrap_or(String::new()));
+		}
+
+		return Err(CacheError::ClBuildError(logs));
+	}
+
+	Ok(())
+}
+
+/// Retains `program` like `clRetainProgram`, counting the reference if the program is tracked
+pub fn retain_program(program: cl_program) -> cl_int {
+	if let Some(entry) = PROGRAMS.lock().unwrap().get_mut(&(program as usize)) {
+		entry.references += 1;
+	}
+
+	unsafe {
+		ll::clRetainProgram(program)
+	}
+}
+
+/// Releases `program` like `clReleaseProgram`. When the last reference of a tracked program
+/// is released, its substitute is released too
+pub fn release_program(program: cl_program) -> cl_int {
+	let released = {
+		let mut programs = PROGRAMS.lock().unwrap();
+		let last = match programs.get_mut(&(program as usize)) {
+			None => false,
+			Some(entry) => {
+				entry.references = entry.references.saturating_sub(1);
+				entry.references == 0
+			},
+		};
+
+		if last { programs.remove(&(program as usize)) } else { None }
+	};
+
+	if let Some(SourceProgram { built: Some(built), .. }) = released {
+		unsafe {
+			ll::clReleaseProgram(built as cl_program);
+		}
+	}
+
+	unsafe {
+		ll::clReleaseProgram(program)
+	}
+}
+
+fn substitute(program: cl_program, built: Program) {
+	let previous = {
+		let mut programs = PROGRAMS.lock().unwrap();
+		// Programs that were not tracked since their creation have the reference of the application
+		let entry = programs.entry(program as usize).or_insert(SourceProgram { references: 1, built: None });
+		mem::replace(&mut entry.built, Some(built.get_id() as usize))
+	};
+
+	// The reference is owned by the registry until the source program is released or built again
+	mem::forget(built);
+
+	if let Some(previous) = previous {
+		unsafe {
+			ll::clReleaseProgram(previous as cl_program);
+		}
+	}
+}
+
+fn remove_substitute(program: cl_program) {
+	let built = PROGRAMS.lock().unwrap().get_mut(&(program as usize)).and_then(|entry| entry.built.take());
+
+	if let Some(built) = built {
+		unsafe {
+			ll::clReleaseProgram(built as cl_program);
+		}
+	}
+}
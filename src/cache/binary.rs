@@ -0,0 +1,100 @@
+use std::sync::Arc;
+use std::ops::Deref;
+use std::fmt::{Debug, Formatter, Result};
+
+/// Immutable program binary shared between the backends and the `Cache`.
+/// Cloning it only increases a reference count, so the binaries are not copied
+/// on their way from the backend to `Program::from_binary`.
+#[derive(Clone)]
+pub struct Binary {
+	data: Arc<AsRef<[u8]> + Send + Sync>,
+	start: usize,
+}
+
+impl Binary {
+	/// Wraps any storage that can be seen as a slice of bytes (for example, a memory mapped file)
+	pub fn from_storage<T>(storage: T) -> Binary where T: AsRef<[u8]> + Send + Sync + 'static {
+		Binary {
+			data: Arc::new(storage),
+			start: 0,
+		}
+	}
+
+	pub fn from_shared(data: Arc<[u8]>) -> Binary {
+		Self::from_storage(data)
+	}
+
+	pub fn from_static(data: &'static [u8]) -> Binary {
+		Self::from_storage(data)
+	}
+
+	pub fn as_slice(&self) -> &[u8] {
+		&(*self.data).as_ref()[self.start..]
+	}
+
+	/// Returns the bytes after `offset`, sharing the same storage
+	pub fn skip(&self, offset: usize) -> Binary {
+		assert!(offset <= self.len(), "Offset {} out of a binary of {} bytes", offset, self.len());
+
+		Binary {
+			data: self.data.clone(),
+			start: self.start + offset,
+		}
+	}
+}
+
+impl From<Vec<u8>> for Binary {
+	fn from(data: Vec<u8>) -> Binary {
+		Self::from_storage(data)
+	}
+}
+
+impl Deref for Binary {
+	type Target = [u8];
+
+	fn deref(&self) -> &[u8] {
+		self.as_slice()
+	}
+}
+
+impl AsRef<[u8]> for Binary {
+	fn as_ref(&self) -> &[u8] {
+		self.as_slice()
+	}
+}
+
+impl PartialEq for Binary {
+	fn eq(&self, other: &Binary) -> bool {
+		self.as_slice() == other.as_slice()
+	}
+}
+
+impl Debug for Binary {
+	fn fmt(&self, f: &mut Formatter) -> Result {
+		write!(f, "Binary({} bytes)", self.len())
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn it_does_not_copy_the_data_when_cloned() {
+		let binary = Binary::from(vec![1u8, 2, 3]);
+		let cloned = binary.clone();
+
+		assert_eq!(binary.as_ptr(), cloned.as_ptr());
+		assert_eq!(&cloned[..], &[1u8, 2, 3][..]);
+	}
+
+	#[test]
+	fn it_skips_bytes_without_copying() {
+		let binary = Binary::from(vec![1u8, 2, 3]);
+		let skipped = binary.skip(1);
+
+		assert_eq!(&skipped[..], &[2u8, 3][..]);
+		assert_eq!(skipped.as_ptr(), binary[1..].as_ptr());
+		assert_eq!(skipped.skip(2).len(), 0);
+	}
+}
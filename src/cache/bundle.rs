@@ -0,0 +1,445 @@
+use cache::{CacheBackend, KeyError};
+use cache::binary::Binary;
+use cache::compressed::decompress;
+use cache::envelope::EntryMetadata;
+use cache::pack::PackBackend;
+use cache::signed::Signer;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs::remove_file;
+use std::io::ErrorKind;
+use std::time::{SystemTime, UNIX_EPOCH};
+use yaml_rust::{Yaml, YamlLoader};
+
+/// Key of the manifest on the pack. It can not collide with the keys of the entries, which are hashes
+const MANIFEST_KEY: &'static str = "!manifest";
+const MANIFEST_VERSION: i64 = 1;
+/// Length of the hashes on the keys. Tag keys are the tag followed by a hash
+const HASH_LENGTH: usize = 64;
+
+/// Criteria to choose the entries of a bundle. An entry is exported if it matches all of them
+#[derive(Clone, Debug, PartialEq)]
+pub enum Selection {
+	/// Entries stored with this tag
+	Tag(String),
+	/// Entries of the devices whose name contains the text
+	Device(String),
+	/// Entries of the platforms whose version contains the text
+	Platform(String),
+}
+
+/// Description of an entry of a bundle
+#[derive(Clone, Debug, PartialEq)]
+pub struct ManifestEntry {
+	pub key: String,
+	pub size: u64,
+	/// SHA256 of the entry as it is stored
+	pub sha256: String,
+	pub tag: Option<String>,
+	/// Metadata stored with the binary. Entries stored by old versions do not have it
+	pub metadata: Option<EntryMetadata>,
+	pub signed: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Manifest {
+	pub created_at: u64,
+	pub entries: Vec<ManifestEntry>,
+}
+
+#[derive(Debug)]
+pub enum BundleError {
+	KeyError(KeyError),
+	InvalidManifest(String),
+	/// An entry does not match the manifest (or its signature is not valid). It holds the key and the reason
+	InvalidEntry(String, String),
+	/// The selections do not match any entry of the backend
+	EmptySelection,
+}
+
+/// Writes the selected entries of `backend` to a bundle on `path`, replacing any previous file.
+/// The bundle is a pack (see `PackBackend`) with a manifest that describes each entry. Compressed
+/// entries (see `CompressedBackend`) are exported decompressed, so their metadata can be read
+pub fn export(backend: &CacheBackend, selections: &[Selection], path: &str) -> Result<Manifest, BundleError> {
+	let mut keys = try!{backend.keys()};
+	keys.sort();
+
+	let mut entries = Vec::new();
+	let mut manifest = Manifest {
+		created_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0),
+		entries: Vec::new(),
+	};
+
+	for key in keys {
+		let entry = match backend.get(&key).and_then(decompress) {
+			Ok(entry) => entry,
+			Err(error) => {
+				warn!("Skipping key {} on the bundle: {:?}", key, error);
+				continue;
+			},
+		};
+
+		let description = describe(&key, &entry);
+		if selections.iter().all(|selection| matches(&description, selection)) {
+			manifest.entries.push(description);
+			entries.push((key, entry));
+		}
+	}
+
+	if !selections.is_empty() && entries.is_empty() {
+		return Err(BundleError::EmptySelection);
+	}
+
+	if let Err(error) = remove_file(path) {
+		if error.kind() != ErrorKind::NotFound {
+			return Err(BundleError::KeyError(KeyError::from(error)));
+		}
+	}
+
+	let mut pack = try!{PackBackend::open(path.to_string())};
+	try!{pack.put_all(&entries)};
+	try!{pack.put(&MANIFEST_KEY.to_string(), &Binary::from(manifest.to_yaml().into_bytes()))};
+
+	Ok(manifest)
+}
+
+/// Reads the manifest of the bundle on `path`
+pub fn read_manifest(path: &str) -> Result<Manifest, BundleError> {
+	let pack = try!{PackBackend::open_read_only(path.to_string())};
+
+	read_pack_manifest(&pack)
+}
+
+/// Stores all the entries of the bundle on `backend`. Before anything is stored, the entries are
+/// checked against the manifest and, with a `signer`, their signatures are verified. If any
+/// of them is not valid, nothing is imported
+pub fn import(path: &str, backend: &mut CacheBackend, signer: Option<&Signer>) -> Result<Manifest, BundleError> {
+	let pack = try!{PackBackend::open_read_only(path.to_string())};
+	let manifest = try!{read_pack_manifest(&pack)};
+
+	let mut entries = Vec::new();
+	for expected in manifest.entries.iter() {
+		let entry = match pack.get(&expected.key) {
+			Ok(entry) => entry,
+			Err(_) => return Err(invalid_entry(&expected.key, "Not found on the bundle")),
+		};
+
+		if describe(&expected.key, &entry) != *expected {
+			return Err(invalid_entry(&expected.key, "It does not match the manifest"));
+		}
+
+		if let Some(signer) = signer {
+			if let Err(error) = signer.verify(&expected.key, &entry) {
+				return Err(invalid_entry(&expected.key, &error.to_string()));
+			}
+		}
+
+		entries.push((expected.key.clone(), entry));
+	}
+
+	try!{backend.put_all(&entries)};
+
+	Ok(manifest)
+}
+
+fn read_pack_manifest(pack: &PackBackend) -> Result<Manifest, BundleError> {
+	let manifest = match pack.get(&MANIFEST_KEY.to_string()) {
+		Ok(manifest) => manifest,
+		Err(_) => return Err(BundleError::InvalidManifest("The bundle does not have a manifest".to_string())),
+	};
+
+	match String::from_utf8(manifest.to_vec()) {
+		Ok(text) => Manifest::from_yaml(&text),
+		Err(_) => Err(BundleError::InvalidManifest("The manifest is not valid UTF-8".to_string())),
+	}
+}
+
+fn describe(key: &String, entry: &Binary) -> ManifestEntry {
+	let mut digester = Sha256::new();
+	digester.input(&entry);
+	let contents = Signer::strip(&entry);
+
+	ManifestEntry {
+		key: key.clone(),
+		size: entry.len() as u64,
+		sha256: digester.result_str(),
+		tag: tag_of(&key),
+		metadata: EntryMetadata::read(&contents).ok().map(|(metadata, _)| metadata),
+		signed: contents.len() != entry.len(),
+	}
+}
+
+fn matches(entry: &ManifestEntry, selection: &Selection) -> bool {
+	match *selection {
+		Selection::Tag(ref tag) => entry.tag.as_ref() == Some(tag),
+		Selection::Device(ref device) => entry.metadata.as_ref().map(|x| x.device_name.contains(device)).unwrap_or(false),
+		Selection::Platform(ref platform) => entry.metadata.as_ref().map(|x| x.platform_version.contains(platform)).unwrap_or(false),
+	}
+}
+
+/// Returns the tag of a key created with `KeyHasher::get_tag_key`
+pub fn tag_of(key: &str) -> Option<String> {
+	if key.len() <= HASH_LENGTH {
+		return None;
+	}
+
+	let (tag, hash) = key.split_at(key.len() - HASH_LENGTH);
+	if hash.chars().all(|c| c.is_digit(16)) {
+		Some(tag.to_string())
+	} else {
+		None
+	}
+}
+
+fn invalid_entry(key: &str, reason: &str) -> BundleError {
+	BundleError::InvalidEntry(key.to_string(), reason.to_string())
+}
+
+impl Manifest {
+	pub fn to_yaml(&self) -> String {
+		let mut yaml = format!("version: {}\ncreated_at: {}\nentries:\n", MANIFEST_VERSION, self.created_at);
+
+		for entry in self.entries.iter() {
+			yaml.push_str(&format!("  - key: {}\n", quote(&entry.key)));
+			yaml.push_str(&format!("    size: {}\n", entry.size));
+			yaml.push_str(&format!("    sha256: {}\n", quote(&entry.sha256)));
+			yaml.push_str(&format!("    signed: {}\n", entry.signed));
+
+			if let Some(ref tag) = entry.tag {
+				yaml.push_str(&format!("    tag: {}\n", quote(tag)));
+			}
+
+			if let Some(ref metadata) = entry.metadata {
+				yaml.push_str(&format!("    device: {}\n", quote(&metadata.device_name)));
+				yaml.push_str(&format!("    platform_version: {}\n", quote(&metadata.platform_version)));
+				yaml.push_str(&format!("    options: {}\n", quote(&metadata.options)));
+				yaml.push_str(&format!("    source_hash: {}\n", quote(&metadata.source_hash)));
+			}
+		}
+
+		yaml
+	}
+
+	pub fn from_yaml(text: &str) -> Result<Manifest, BundleError> {
+		let docs = match YamlLoader::load_from_str(text) {
+			Ok(docs) => docs,
+			Err(error) => return Err(BundleError::InvalidManifest(format!("{:?}", error))),
+		};
+
+		let doc = match docs.first() {
+			Some(doc) => doc,
+			None => return Err(BundleError::InvalidManifest("Empty manifest".to_string())),
+		};
+
+		match doc["version"].as_i64() {
+			Some(MANIFEST_VERSION) => (),
+			other => return Err(BundleError::InvalidManifest(format!("Unsupported manifest version: {:?}", other))),
+		}
+
+		let mut entries = Vec::new();
+		if let Some(yaml_entries) = doc["entries"].as_vec() {
+			for yaml_entry in yaml_entries {
+				entries.push(try!{ManifestEntry::from_yaml(yaml_entry)});
+			}
+		}
+
+		Ok(Manifest {
+			created_at: doc["created_at"].as_i64().unwrap_or(0) as u64,
+			entries: entries,
+		})
+	}
+}
+
+impl ManifestEntry {
+	fn from_yaml(yaml: &Yaml) -> Result<ManifestEntry, BundleError> {
+		let field = |name: &str| yaml[name].as_str().map(|x| x.to_string());
+		let key = try!{field("key").ok_or(BundleError::InvalidManifest("Entry without key".to_string()))};
+
+		let metadata = match (field("device"), field("platform_version"), field("options"), field("source_hash")) {
+			(Some(device), Some(platform_version), Some(options), Some(source_hash)) => Some(EntryMetadata {
+				device_name: device,
+				platform_version: platform_version,
+				options: options,
+				source_hash: source_hash,
+			}),
+			_ => None,
+		};
+
+		Ok(ManifestEntry {
+			size: try!{yaml["size"].as_i64().ok_or(invalid_entry(&key, "Entry without size"))} as u64,
+			sha256: try!{field("sha256").ok_or(invalid_entry(&key, "Entry without hash"))},
+			tag: field("tag"),
+			metadata: metadata,
+			signed: yaml["signed"].as_bool().unwrap_or(false),
+			key: key,
+		})
+	}
+}
+
+/// Double quoted YAML scalar, so any key or option is read back as it is
+fn quote(value: &str) -> String {
+	let mut quoted = String::from("\"");
+
+	for c in value.chars() {
+		match c {
+			'"' => quoted.push_str("\\\""),
+			'\\' => quoted.push_str("\\\\"),
+			'\n' => quoted.push_str("\\n"),
+			'\t' => quoted.push_str("\\t"),
+			c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+			c => quoted.push(c),
+		}
+	}
+
+	quoted.push('"');
+	quoted
+}
+
+impl From<KeyError> for BundleError {
+	fn from(error: KeyError) -> Self {
+		BundleError::KeyError(error)
+	}
+}
+
+impl Display for BundleError {
+	fn fmt(&self, f: &mut Formatter) -> FmtResult {
+		match *self {
+			BundleError::KeyError(KeyError::Unsupported) => write!(f, "The backend can not list its entries"),
+			BundleError::KeyError(ref error) => write!(f, "Backend error: {:?}", error),
+			BundleError::InvalidManifest(ref reason) => write!(f, "Invalid manifest: {}", reason),
+			BundleError::InvalidEntry(ref key, ref reason) => write!(f, "Invalid entry {}: {}", key, reason),
+			BundleError::EmptySelection => write!(f, "No entry matches the selection"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cache::CacheBackend;
+	use cache::binary::Binary;
+	use cache::compressed::CompressedBackend;
+	use cache::envelope::EntryMetadata;
+	use cache::pack::PackBackend;
+	use cache::signed::Signer;
+	use cache::volatile::{SharedBackend, Volatile};
+	use std::cell::RefCell;
+	use std::env;
+	use std::fs::remove_file;
+	use std::process;
+	use std::rc::Rc;
+
+	const HASH: &'static str = "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+	#[test]
+	fn it_exports_and_imports_the_selected_entries() {
+		let path = bundle_path("selected");
+		let mut source = Volatile::new();
+		source.put(&format!("kernels{}", HASH), &entry("GPU A")).unwrap();
+		source.put(&HASH.to_string(), &entry("GPU B")).unwrap();
+
+		let manifest = export(&source, &[Selection::Tag("kernels".to_string())], &path).unwrap();
+		assert_eq!(manifest.entries.len(), 1);
+		assert_eq!(manifest.entries[0].metadata.as_ref().unwrap().device_name, "GPU A");
+
+		let mut target = Volatile::new();
+		assert_eq!(import(&path, &mut target, None).unwrap(), read_manifest(&path).unwrap());
+		assert_eq!(target.get(&format!("kernels{}", HASH)).unwrap(), entry("GPU A"));
+		assert!(target.get(&HASH.to_string()).is_err());
+
+		remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn it_does_not_import_modified_or_unsigned_entries() {
+		let path = bundle_path("modified");
+		let mut source = Volatile::new();
+		source.put(&HASH.to_string(), &entry("GPU A")).unwrap();
+		export(&source, &[Selection::Device("GPU".to_string())], &path).unwrap();
+
+		let mut target = Volatile::new();
+		match import(&path, &mut target, Some(&Signer::new(b"secret"))) {
+			Err(BundleError::InvalidEntry(ref key, _)) if *key == HASH => (),
+			other => panic!("Unsigned entries should be rejected, found {:?}", other),
+		}
+
+		PackBackend::open(path.clone()).unwrap().put(&HASH.to_string(), &entry("GPU B")).unwrap();
+		match import(&path, &mut target, None) {
+			Err(BundleError::InvalidEntry(ref key, _)) if *key == HASH => (),
+			other => panic!("Modified entries should be rejected, found {:?}", other),
+		}
+		assert!(target.keys().unwrap().is_empty());
+
+		remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn it_exports_the_compressed_entries_decompressed() {
+		let path = bundle_path("compressed");
+		let inner = Rc::new(RefCell::new(Volatile::new()));
+		let mut compressed = CompressedBackend::new(Box::new(SharedBackend(inner.clone())));
+		compressed.set_threshold(0);
+		compressed.put(&HASH.to_string(), &entry("GPU A")).unwrap();
+		assert!(inner.borrow().get(&HASH.to_string()).unwrap().starts_with(b"CLCZ"));
+
+		let manifest = export(&*inner.borrow(), &[Selection::Device("GPU A".to_string())], &path).unwrap();
+		assert_eq!(manifest.entries.len(), 1);
+
+		let mut target = Volatile::new();
+		import(&path, &mut target, None).unwrap();
+		assert_eq!(target.get(&HASH.to_string()).unwrap(), entry("GPU A"));
+
+		remove_file(&path).unwrap();
+	}
+
+	#[test]
+	fn it_does_not_export_an_empty_selection() {
+		let path = bundle_path("empty");
+		let mut source = Volatile::new();
+		source.put(&HASH.to_string(), &entry("GPU A")).unwrap();
+
+		match export(&source, &[Selection::Device("GPU B".to_string())], &path) {
+			Err(BundleError::EmptySelection) => (),
+			other => panic!("An empty selection should be rejected, found {:?}", other),
+		}
+		assert!(PackBackend::open_read_only(path.clone()).is_err());
+	}
+
+	#[test]
+	fn it_reads_back_its_manifests() {
+		let manifest = Manifest {
+			created_at: 42,
+			entries: vec![ManifestEntry {
+				key: HASH.to_string(),
+				size: 3,
+				sha256: HASH.to_string(),
+				tag: None,
+				metadata: Some(metadata("GPU \"A\"\n")),
+				signed: true,
+			}],
+		};
+
+		assert_eq!(Manifest::from_yaml(&manifest.to_yaml()).unwrap(), manifest);
+	}
+
+	fn entry(device: &str) -> Binary {
+		metadata(device).wrap(&Binary::from(vec![1u8, 2, 3]))
+	}
+
+	fn metadata(device: &str) -> EntryMetadata {
+		EntryMetadata {
+			device_name: device.to_string(),
+			platform_version: "OpenCL 1.2".to_string(),
+			options: "-D A=1".to_string(),
+			source_hash: HASH.to_string(),
+		}
+	}
+
+	fn bundle_path(name: &str) -> String {
+		let path = env::temp_dir().join(format!("clcache_bundle_{}_{}.pack", name, process::id())).to_str().unwrap().to_string();
+		let _ = remove_file(&path);
+
+		path
+	}
+}
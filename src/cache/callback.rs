@@ -0,0 +1,166 @@
+use cache::{CacheBackend, KeyError};
+use cache::binary::Binary;
+use libc;
+use std::ffi::CString;
+use std::ptr;
+use std::slice;
+
+/// Return code of the callbacks when the operation succeeded
+pub const CALLBACK_OK: libc::c_int = 0;
+/// Return code of the callbacks when the key does not exist
+pub const CALLBACK_NOT_FOUND: libc::c_int = 1;
+
+/// Looks for the key and, if found, points `payload` and `size` to the binary
+pub type GetCallback = extern "C" fn(
+	user_data: *mut libc::c_void,
+	key: *const libc::c_char,
+	payload: *mut *const u8,
+	size: *mut libc::size_t
+) -> libc::c_int;
+
+/// Called when the library does not need anymore a payload returned by the get callback
+pub type ReleaseCallback = extern "C" fn(
+	user_data: *mut libc::c_void,
+	payload: *const u8,
+	size: libc::size_t
+);
+
+pub type PutCallback = extern "C" fn(
+	user_data: *mut libc::c_void,
+	key: *const libc::c_char,
+	payload: *const u8,
+	size: libc::size_t
+) -> libc::c_int;
+
+pub type DeleteCallback = extern "C" fn(
+	user_data: *mut libc::c_void,
+	key: *const libc::c_char
+) -> libc::c_int;
+
+/// Backend that forwards all the operations to C function pointers, so the hosts
+/// can plug their own storage without writing Rust.
+pub struct CallbackBackend {
+	get: GetCallback,
+	put: PutCallback,
+	delete: Option<DeleteCallback>,
+	release: Option<ReleaseCallback>,
+	user_data: *mut libc::c_void,
+}
+
+impl CallbackBackend {
+	pub fn new(
+		get: GetCallback,
+		put: PutCallback,
+		delete: Option<DeleteCallback>,
+		release: Option<ReleaseCallback>,
+		user_data: *mut libc::c_void
+	) -> CallbackBackend {
+		CallbackBackend {
+			get: get,
+			put: put,
+			delete: delete,
+			release: release,
+			user_data: user_data,
+		}
+	}
+
+	fn c_key(key: &String) -> Result<CString, KeyError> {
+		CString::new(key.clone()).map_err(|_| KeyError::InvalidContent)
+	}
+
+	fn check(code: libc::c_int) -> Result<(), KeyError> {
+		match code {
+			CALLBACK_OK => Ok(()),
+			CALLBACK_NOT_FOUND => Err(KeyError::KeyNotFound),
+			_ => Err(KeyError::IoError),
+		}
+	}
+}
+
+impl CacheBackend for CallbackBackend {
+	fn get(&self, key: &String) -> Result<Binary, KeyError> {
+		let c_key = try!{Self::c_key(key)};
+		let mut payload: *const u8 = ptr::null();
+		let mut size: libc::size_t = 0;
+
+		try!{Self::check((self.get)(self.user_data, c_key.as_ptr(), &mut payload, &mut size))};
+		if payload.is_null() {
+			return Err(KeyError::InvalidContent);
+		}
+
+		// The payload is owned by the host, so it has to be copied before releasing it
+		let binary = unsafe { slice::from_raw_parts(payload, size as usize) }.to_vec();
+		if let Some(release) = self.release {
+			release(self.user_data, payload, size);
+		}
+
+		Ok(Binary::from(binary))
+	}
+
+	fn put(&mut self, key: &String, payload: &Binary) -> Result<(), KeyError> {
+		let c_key = try!{Self::c_key(key)};
+
+		Self::check((self.put)(self.user_data, c_key.as_ptr(), payload.as_ptr(), payload.len() as libc::size_t))
+	}
+
+	fn delete(&mut self, key: &String) -> Result<(), KeyError> {
+		let c_key = try!{Self::c_key(key)};
+
+		match self.delete {
+			None => Err(KeyError::Unsupported),
+			Some(delete) => Self::check(delete(self.user_data, c_key.as_ptr())),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cache::{CacheBackend, KeyError};
+	use cache::binary::Binary;
+	use libc;
+	use std::collections::HashMap;
+	use std::ffi::CStr;
+	use std::slice;
+
+	extern "C" fn get(user_data: *mut libc::c_void, key: *const libc::c_char, payload: *mut *const u8, size: *mut libc::size_t) -> libc::c_int {
+		let storage = unsafe { &*(user_data as *const HashMap<String, Vec<u8>>) };
+		let key = unsafe { CStr::from_ptr(key) }.to_string_lossy().into_owned();
+
+		match storage.get(&key) {
+			None => CALLBACK_NOT_FOUND,
+			Some(binary) => {
+				unsafe {
+					*payload = binary.as_ptr();
+					*size = binary.len() as libc::size_t;
+				}
+
+				CALLBACK_OK
+			},
+		}
+	}
+
+	extern "C" fn put(user_data: *mut libc::c_void, key: *const libc::c_char, payload: *const u8, size: libc::size_t) -> libc::c_int {
+		let storage = unsafe { &mut *(user_data as *mut HashMap<String, Vec<u8>>) };
+		let key = unsafe { CStr::from_ptr(key) }.to_string_lossy().into_owned();
+		storage.insert(key, unsafe { slice::from_raw_parts(payload, size as usize) }.to_vec());
+
+		CALLBACK_OK
+	}
+
+	#[test]
+	fn it_forwards_the_operations_to_the_callbacks() {
+		let mut storage: HashMap<String, Vec<u8>> = HashMap::new();
+		let user_data = &mut storage as *mut HashMap<String, Vec<u8>> as *mut libc::c_void;
+		let mut backend = CallbackBackend::new(get, put, None, None, user_data);
+
+		backend.put(&"key".to_string(), &Binary::from(vec![1, 2])).unwrap();
+
+		assert_eq!(backend.get(&"key".to_string()).unwrap(), Binary::from(vec![1, 2]));
+		assert!(backend.get(&"missing".to_string()).is_err());
+		match backend.delete(&"key".to_string()) {
+			Err(KeyError::Unsupported) => (),
+			_ => panic!("Delete should not be supported without callback"),
+		}
+	}
+}
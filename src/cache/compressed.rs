@@ -0,0 +1,267 @@
+use cache::{CacheBackend, KeyError};
+use cache::binary::Binary;
+use flate2::Compression;
+use flate2::read::ZlibDecoder;
+use flate2::write::ZlibEncoder;
+use std::io::{Read, Write};
+
+/// Compressed entries start with this magic, followed by the codec byte. Deflate entries
+/// also store the length of the binary (8 bytes, little endian) before the stream
+const MAGIC: &'static [u8] = b"CLCZ";
+const LENGTH_SIZE: usize = 8;
+
+/// zlib can not expand the data more than ~1032 times, so longer lengths are corrupted
+const MAX_RATIO: u64 = 1032;
+
+/// Binaries smaller than this are stored as they are
+pub const DEFAULT_THRESHOLD: usize = 4096;
+
+/// Codec used on an entry. It's stored on every entry, so the entries stored with distinct
+/// settings (or before the compression was enabled) can be read from the same backend
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Codec {
+	/// The binary is stored as it is
+	Stored,
+	/// zlib stream
+	Deflate,
+}
+
+impl Codec {
+	fn to_byte(&self) -> u8 {
+		match *self {
+			Codec::Stored => 0,
+			Codec::Deflate => 1,
+		}
+	}
+
+	fn from_byte(byte: u8) -> Option<Codec> {
+		match byte {
+			0 => Some(Codec::Stored),
+			1 => Some(Codec::Deflate),
+			_ => None,
+		}
+	}
+}
+
+/// Backend that compresses the binaries before storing them on another backend
+pub struct CompressedBackend {
+	backend: Box<CacheBackend>,
+	codec: Codec,
+	threshold: usize,
+}
+
+impl CompressedBackend {
+	pub fn new(backend: Box<CacheBackend>) -> CompressedBackend {
+		CompressedBackend {
+			backend: backend,
+			codec: Codec::Deflate,
+			threshold: DEFAULT_THRESHOLD,
+		}
+	}
+
+	/// Sets the codec of the new entries. `Codec::Stored` disables the compression, but the
+	/// compressed entries are still readable
+	pub fn set_codec(&mut self, codec: Codec) {
+		self.codec = codec;
+	}
+
+	/// Binaries smaller than `threshold` bytes are not compressed
+	pub fn set_threshold(&mut self, threshold: usize) {
+		self.threshold = threshold;
+	}
+
+	fn encode(&self, payload: &Binary) -> Result<Binary, KeyError> {
+		let codec = if payload.len() < self.threshold { Codec::Stored } else { self.codec };
+
+		let mut entry = Vec::with_capacity(payload.len() + MAGIC.len() + 1);
+		entry.extend_from_slice(MAGIC);
+		entry.push(codec.to_byte());
+
+		match codec {
+			Codec::Stored => entry.extend_from_slice(&payload),
+			Codec::Deflate => {
+				write_u64(&mut entry, payload.len() as u64);
+				let mut encoder = ZlibEncoder::new(entry, Compression::Default);
+				try!{encoder.write_all(&payload)};
+				entry = try!{encoder.finish()};
+
+				// Binaries that do not compress are cheaper to read as they are
+				if entry.len() >= payload.len() + MAGIC.len() + 1 {
+					entry.truncate(MAGIC.len());
+					entry.push(Codec::Stored.to_byte());
+					entry.extend_from_slice(&payload);
+				}
+			},
+		}
+
+		Ok(Binary::from(entry))
+	}
+}
+
+impl CacheBackend for CompressedBackend {
+	fn get(&self, key: &String) -> Result<Binary, KeyError> {
+		let entry = try!{self.backend.get(key)};
+
+		decompress(entry)
+	}
+
+	fn put(&mut self, key: &String, payload: &Binary) -> Result<(), KeyError> {
+		let entry = try!{self.encode(payload)};
+
+		self.backend.put(key, &entry)
+	}
+
+	fn put_all(&mut self, entries: &[(String, Binary)]) -> Result<(), KeyError> {
+		let mut encoded = Vec::new();
+		for &(ref key, ref payload) in entries {
+			encoded.push((key.clone(), try!{self.encode(payload)}));
+		}
+
+		self.backend.put_all(&encoded)
+	}
+
+	fn delete(&mut self, key: &String) -> Result<(), KeyError> {
+		self.backend.delete(key)
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.backend.is_read_only()
+	}
+
+	fn keys(&self) -> Result<Vec<String>, KeyError> {
+		self.backend.keys()
+	}
+}
+
+/// Returns the binary stored on an entry of a `CompressedBackend`, so the raw entries of its
+/// backend can be read
+pub fn decompress(entry: Binary) -> Result<Binary, KeyError> {
+	// Entries stored without this backend are returned as they are
+	if entry.len() <= MAGIC.len() || &entry[..MAGIC.len()] != MAGIC {
+		return Ok(entry);
+	}
+
+	match Codec::from_byte(entry[MAGIC.len()]) {
+		Some(Codec::Stored) => Ok(entry.skip(MAGIC.len() + 1)),
+		Some(Codec::Deflate) => {
+			let stream_start = MAGIC.len() + 1 + LENGTH_SIZE;
+			if entry.len() < stream_start {
+				return Err(KeyError::InvalidContent);
+			}
+
+			let length = read_u64(&entry[MAGIC.len() + 1..stream_start]);
+			if length > (entry.len() - stream_start) as u64 * MAX_RATIO {
+				warn!("Compressed entry with an invalid length: {}", length);
+				return Err(KeyError::InvalidContent);
+			}
+
+			// The stream is never read past the stored length, so a corrupted entry can not exhaust the memory
+			let mut payload = Vec::with_capacity(length as usize);
+			let mut decoder = ZlibDecoder::new(&entry[stream_start..]).take(length + 1);
+			if let Err(error) = decoder.read_to_end(&mut payload) {
+				warn!("Could not decompress entry: {}", error);
+				return Err(KeyError::InvalidContent);
+			}
+
+			if payload.len() as u64 != length {
+				warn!("Compressed entry of {} bytes, expected {}", payload.len(), length);
+				return Err(KeyError::InvalidContent);
+			}
+
+			Ok(Binary::from(payload))
+		},
+		None => {
+			warn!("Unknown codec on entry: {}", entry[MAGIC.len()]);
+			Err(KeyError::InvalidContent)
+		},
+	}
+}
+
+fn write_u64(buffer: &mut Vec<u8>, value: u64) {
+	for i in 0..LENGTH_SIZE {
+		buffer.push((value >> (8 * i)) as u8);
+	}
+}
+
+fn read_u64(bytes: &[u8]) -> u64 {
+	bytes.iter().rev().fold(0, |value, &byte| (value << 8) | byte as u64)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cache::{CacheBackend, KeyError};
+	use cache::volatile::{SharedBackend, Volatile};
+	use cache::binary::Binary;
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	#[test]
+	fn it_compresses_the_binaries_over_the_threshold() {
+		let (inner, mut backend) = create_backend();
+		let payload = Binary::from(vec![7u8; 10000]);
+
+		backend.put(&"key".to_string(), &payload).unwrap();
+
+		assert!(inner.borrow().get(&"key".to_string()).unwrap().len() < payload.len());
+		assert_eq!(backend.get(&"key".to_string()).unwrap(), payload);
+	}
+
+	#[test]
+	fn it_stores_small_binaries_as_they_are() {
+		let (inner, mut backend) = create_backend();
+		let payload = Binary::from(vec![7u8; 100]);
+
+		backend.put(&"key".to_string(), &payload).unwrap();
+
+		assert_eq!(inner.borrow().get(&"key".to_string()).unwrap().len(), payload.len() + 5);
+		assert_eq!(backend.get(&"key".to_string()).unwrap(), payload);
+	}
+
+	#[test]
+	fn it_reads_the_entries_stored_without_compression() {
+		let (inner, mut backend) = create_backend();
+		let payload = Binary::from(vec![1u8, 2, 3]);
+		inner.borrow_mut().put(&"legacy".to_string(), &payload).unwrap();
+		backend.set_codec(Codec::Stored);
+		backend.put(&"stored".to_string(), &Binary::from(vec![7u8; 10000])).unwrap();
+
+		assert_eq!(backend.get(&"legacy".to_string()).unwrap(), payload);
+		assert_eq!(backend.get(&"stored".to_string()).unwrap(), Binary::from(vec![7u8; 10000]));
+	}
+
+	#[test]
+	fn it_rejects_unknown_codecs() {
+		let (inner, backend) = create_backend();
+		inner.borrow_mut().put(&"key".to_string(), &Binary::from(b"CLCZ\x09data".to_vec())).unwrap();
+
+		match backend.get(&"key".to_string()) {
+			Err(KeyError::InvalidContent) => (),
+			other => panic!("Expected invalid content, found {:?}", other),
+		}
+	}
+
+	#[test]
+	fn it_does_not_decompress_past_the_stored_length() {
+		let (inner, mut backend) = create_backend();
+		backend.put(&"key".to_string(), &Binary::from(vec![7u8; 10000])).unwrap();
+
+		let mut entry = inner.borrow().get(&"key".to_string()).unwrap().to_vec();
+		// Length of 100 bytes
+		entry[5] = 100;
+		entry[6] = 0;
+		inner.borrow_mut().put(&"key".to_string(), &Binary::from(entry)).unwrap();
+
+		match backend.get(&"key".to_string()) {
+			Err(KeyError::InvalidContent) => (),
+			other => panic!("Expected invalid content, found {:?}", other),
+		}
+	}
+
+	fn create_backend() -> (Rc<RefCell<Volatile>>, CompressedBackend) {
+		let inner = Rc::new(RefCell::new(Volatile::new()));
+		let backend = CompressedBackend::new(Box::new(SharedBackend(inner.clone())));
+
+		(inner, backend)
+	}
+}
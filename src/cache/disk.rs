@@ -1,57 +1,615 @@
 use cache::{CacheBackend, KeyError};
+use cache::binary::Binary;
+#[cfg(unix)]
+use cache::mmap::MappedFile;
+#[cfg(unix)]
+use libc;
+#[cfg(unix)]
+use std::ffi::CString;
 use std::fs::*;
-use std::io::Write;
+use std::io::{ErrorKind, Write};
 use std::io::Read;
 use std::result::Result;
 use std::io::Result as IoResult;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::atomic::{AtomicUsize, ATOMIC_USIZE_INIT, Ordering};
+
+const LAYOUT_FILE: &'static str = "clcache.layout";
+const LAYOUT_VERSION: u32 = 2;
+
+static TMP_COUNTER: AtomicUsize = ATOMIC_USIZE_INIT;
+
+/// Files smaller than this are read instead of mapped, as the copy is cheaper than the mapping
+const MIN_MAPPED_SIZE: u64 = 16 * 1024;
+
+/// Describes how the entries are distributed on the cache directory
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Layout {
+	/// All the entries are stored as `<key>.clbin` on the cache directory (layout version 1)
+	Flat,
+	/// The entries are stored on `levels` nested directories, each one named with
+	/// the next `width` characters of the key. For example, `ab/cd/<key>.clbin`
+	Sharded { levels: usize, width: usize },
+}
+
+impl Layout {
+	pub fn sharded() -> Layout {
+		Layout::Sharded { levels: 2, width: 2 }
+	}
+
+	fn relative_path(&self, key: &String) -> String {
+		match *self {
+			Layout::Flat => format!("{}.clbin", key),
+			Layout::Sharded { levels, width } => {
+				let chars: Vec<char> = key.chars().collect();
+				let mut path = String::new();
+
+				for chunk in chars.chunks(width).take(levels) {
+					if chunk.len() < width {
+						break;
+					}
+
+					path.extend(chunk.iter());
+					path.push('/');
+				}
+
+				format!("{}{}.clbin", path, key)
+			},
+		}
+	}
+
+	fn to_marker(&self) -> String {
+		match *self {
+			Layout::Flat => format!("clcache-layout {}\nflat\n", LAYOUT_VERSION),
+			Layout::Sharded { levels, width } => format!("clcache-layout {}\nsharded {} {}\n", LAYOUT_VERSION, levels, width),
+		}
+	}
+
+	fn from_marker(marker: &str) -> Option<Layout> {
+		let mut lines = marker.lines();
+		if lines.next().map(|x| x.trim().to_string()) != Some(format!("clcache-layout {}", LAYOUT_VERSION)) {
+			return None;
+		}
+
+		let fields: Vec<&str> = lines.next().unwrap_or("").split_whitespace().collect();
+		if fields.len() == 1 && fields[0] == "flat" {
+			return Some(Layout::Flat);
+		}
+
+		if fields.len() != 3 || fields[0] != "sharded" {
+			return None;
+		}
+
+		match (fields[1].parse::<usize>(), fields[2].parse::<usize>()) {
+			(Ok(levels), Ok(width)) if width > 0 => Some(Layout::Sharded { levels: levels, width: width }),
+			_ => None,
+		}
+	}
+
+	/// Reads the layout of a cache directory. Directories without the layout file
+	/// were created by previous versions, so they use the flat layout
+	fn detect(path: &String) -> Option<Layout> {
+		let mut file = match File::open(Path::new(path).join(LAYOUT_FILE)) {
+			Err(_) => return Some(Layout::Flat),
+			Ok(file) => file,
+		};
+
+		let mut marker = String::new();
+		if file.read_to_string(&mut marker).is_err() {
+			return None;
+		}
+
+		let layout = Layout::from_marker(&marker);
+		if layout.is_none() {
+			warn!("Unknown layout on cache directory {}", path);
+		}
+
+		layout
+	}
+}
 
 pub struct FileSystemCache {
 	path: String,
+	read_only: bool,
+	mmap: bool,
+	layout: Layout,
+	max_size: Option<u64>,
+	/// Size of the binaries as tracked by this instance since the directory was last scanned.
+	/// Other processes may store entries too, so it's only used to decide when to scan again
+	estimated_size: Option<u64>,
 }
 
 impl FileSystemCache {
 	pub fn new(path: String) -> Option<FileSystemCache> {
 		// Check that route exists, and try to crate it
 		let path_meta = metadata(&path);
-		match path_meta {
+		let read_only = match path_meta {
 			Err(_) => match Self::create_dir(&path) {
 				Err(_) => return None,
-				_ => (),
+				_ => false,
 			},
-			_ => (),
+			Ok(meta) => {
+				if !meta.is_dir() {
+					return None;
+				}
+
+				// An existing directory that the current user can not write can only be used to serve hits
+				let writable = Self::is_writable(&path, &meta);
+				if !writable {
+					warn!("Cache directory {} is not writable: Opening it as read-only", path);
+				}
+
+				!writable
+			},
+		};
+
+		let layout = match Layout::detect(&path) {
+			None => return None,
+			Some(layout) => layout,
+		};
+
+		let cache = FileSystemCache {
+			path: path,
+			read_only: read_only,
+			mmap: cfg!(unix),
+			layout: layout,
+			max_size: None,
+			estimated_size: None,
+		};
+
+		Some(cache)
+	}
+
+	/// Opens the cache with the given layout. If the directory was using another
+	/// layout, the existing entries are migrated to the new one
+	pub fn with_layout(path: String, layout: Layout) -> Option<FileSystemCache> {
+		let mut cache = match Self::new(path) {
+			None => return None,
+			Some(cache) => cache,
+		};
+
+		if cache.layout != layout || !cache.has_layout_file() {
+			if cache.read_only {
+				warn!("Can not change the layout of a read-only cache: Using {:?}", cache.layout);
+				return Some(cache);
+			}
+
+			if let Err(error) = cache.migrate(layout) {
+				warn!("Could not migrate cache {} to layout {:?}: {:?}", cache.path, layout, error);
+				return None;
+			}
 		}
 
+		Some(cache)
+	}
+
+	/// Opens an existing directory without ever creating or writing anything on it.
+	/// It's intended to be used with shared caches populated by an administrator
+	pub fn read_only(path: String) -> Option<FileSystemCache> {
+		match metadata(&path) {
+			Ok(ref meta) if meta.is_dir() => (),
+			_ => return None,
+		}
+
+		let layout = match Layout::detect(&path) {
+			None => return None,
+			Some(layout) => layout,
+		};
+
 		let cache = FileSystemCache {
 			path: path,
+			read_only: true,
+			mmap: cfg!(unix),
+			layout: layout,
+			max_size: None,
+			estimated_size: None,
 		};
 
 		Some(cache)
 	}
 
+	/// Checks if the current user can create files on the directory. The permission bits alone
+	/// do not tell it, as they do not take the owner of the directory into account
+	#[cfg(unix)]
+	fn is_writable(path: &String, _: &Metadata) -> bool {
+		let c_path = match CString::new(path.as_bytes()) {
+			Err(_) => return false,
+			Ok(c_path) => c_path,
+		};
+
+		unsafe { libc::access(c_path.as_ptr(), libc::W_OK) == 0 }
+	}
+
+	#[cfg(not(unix))]
+	fn is_writable(_: &String, meta: &Metadata) -> bool {
+		!meta.permissions().readonly()
+	}
+
 	pub fn create_dir(path: &String) ->IoResult<()> {
 		Ok(try!{create_dir_all(path)})
 	}
 
+	/// Enables or disables the memory mapped reads (enabled by default on unix) of the files of
+	/// at least 16 KiB. When disabled, or if the file can not be mapped, the binaries are read
+	/// into memory. A mapped binary raises SIGBUS if its file is truncated while it's used: this
+	/// cache only replaces the files (see `put`), so disable it if anything else may truncate them
+	pub fn set_mmap(&mut self, enabled: bool) {
+		self.mmap = enabled && cfg!(unix);
+	}
+
+	pub fn layout(&self) -> Layout {
+		self.layout
+	}
+
+	/// Once set, the oldest entries are removed when a store takes the size of the
+	/// binaries over `max_size` bytes
+	pub fn set_max_size(&mut self, max_size: Option<u64>) {
+		self.max_size = max_size;
+		self.estimated_size = None;
+	}
+
+	/// Removes the least recently stored entries until the size of all of them is below
+	/// `max_size`. Returns the amount of removed entries
+	pub fn shrink_to(&mut self, max_size: u64) -> Result<usize, KeyError> {
+		if self.read_only {
+			return Err(KeyError::ReadOnly);
+		}
+
+		let mut entries = Vec::new();
+		let mut total = 0;
+		for key in try!{self.keys()} {
+			let meta = try!{metadata(self.get_path(&key))};
+			total += meta.len();
+			entries.push((try!{meta.modified()}, meta.len(), key));
+		}
+
+		entries.sort();
+		let mut removed = 0;
+		for (_, size, key) in entries {
+			if total <= max_size {
+				break;
+			}
+
+			try!{remove_file(self.get_path(&key))};
+			total -= size;
+			removed += 1;
+		}
+
+		self.estimated_size = Some(total);
+
+		Ok(removed)
+	}
+
+	/// Updates the estimated size after storing `stored` bytes on place of `replaced` ones, and
+	/// shrinks the cache only when the estimation goes over the limit. The directory is only
+	/// scanned the first time and on each shrink, so the stores do not depend on the amount of entries
+	fn enforce_max_size(&mut self, max_size: u64, replaced: u64, stored: u64) -> Result<(), KeyError> {
+		let estimated_size = match self.estimated_size {
+			Some(size) => size.saturating_sub(replaced) + stored,
+			None => try!{self.stored_size()},
+		};
+
+		self.estimated_size = Some(estimated_size);
+		if estimated_size > max_size {
+			try!{self.shrink_to(max_size)};
+		}
+
+		Ok(())
+	}
+
+	fn stored_size(&self) -> Result<u64, KeyError> {
+		let mut total = 0;
+		for key in try!{self.keys()} {
+			total += try!{metadata(self.get_path(&key))}.len();
+		}
+
+		Ok(total)
+	}
+
+	/// Moves all the entries to the given layout. Returns the amount of moved entries
+	pub fn migrate(&mut self, layout: Layout) -> Result<usize, KeyError> {
+		if self.read_only {
+			return Err(KeyError::ReadOnly);
+		}
+
+		let keys = try!{self.keys()};
+		let mut moved = 0;
+
+		for key in keys.iter() {
+			let current_path = self.get_path(key);
+			let new_path = format!("{}/{}", self.path, layout.relative_path(key));
+
+			if current_path != new_path {
+				try!{Self::create_parent(&new_path)};
+				try!{rename(&current_path, &new_path)};
+				moved += 1;
+			}
+		}
+
+		if let Layout::Sharded { .. } = self.layout {
+			self.remove_empty_dirs(&PathBuf::from(&self.path));
+		}
+
+		let mut marker = try!{File::create(Path::new(&self.path).join(LAYOUT_FILE))};
+		try!{marker.write_all(layout.to_marker().as_bytes())};
+		self.layout = layout;
+		info!("Migrated {} entries of cache {} to layout {:?}", moved, self.path, layout);
+
+		Ok(moved)
+	}
+
+	/// Returns the keys of all the entries stored on the cache
+	pub fn keys(&self) -> Result<Vec<String>, KeyError> {
+		let mut keys = Vec::new();
+		try!{Self::collect_keys(&PathBuf::from(&self.path), &mut keys)};
+
+		Ok(keys)
+	}
+
+	fn collect_keys(dir: &PathBuf, keys: &mut Vec<String>) -> IoResult<()> {
+		for entry in try!{read_dir(dir)} {
+			let path = try!{entry}.path();
+
+			if try!{metadata(&path)}.is_dir() {
+				try!{Self::collect_keys(&path, keys)};
+			} else if path.extension().and_then(|x| x.to_str()) == Some("clbin") {
+				if let Some(key) = path.file_stem().and_then(|x| x.to_str()) {
+					keys.push(key.to_string());
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	fn remove_empty_dirs(&self, dir: &PathBuf) {
+		if let Ok(entries) = read_dir(dir) {
+			for entry in entries {
+				if let Ok(entry) = entry {
+					let path = entry.path();
+					if path.is_dir() {
+						self.remove_empty_dirs(&path);
+						// It only succeeds if the directory is empty
+						let _ = remove_dir(&path);
+					}
+				}
+			}
+		}
+	}
+
+	fn has_layout_file(&self) -> bool {
+		metadata(Path::new(&self.path).join(LAYOUT_FILE)).is_ok()
+	}
+
+	fn create_parent(path: &String) -> IoResult<()> {
+		match Path::new(path).parent() {
+			None => Ok(()),
+			Some(parent) => create_dir_all(parent),
+		}
+	}
+
+	/// Writes the file on a temporary path and renames it. The temporary name is unique
+	/// among the processes (and threads) that share the directory, so concurrent writers
+	/// can not mix their contents
+	fn write_atomically(final_path: &String, payload: &[u8]) -> IoResult<()> {
+		let tmp_path = format!("{}.{}.{}.tmp", final_path, process::id(), TMP_COUNTER.fetch_add(1, Ordering::SeqCst));
+
+		let result = File::create(&tmp_path).
+			and_then(|mut f| f.write_all(payload)).
+			and_then(|_| rename(&tmp_path, final_path));
+
+		if result.is_err() {
+			let _ = remove_file(&tmp_path);
+		}
+
+		result
+	}
+
 	fn get_path(&self, key: &String) -> String {
-    	format!("{}/{}.clbin", self.path.clone(), key)
+    	format!("{}/{}", self.path.clone(), self.layout.relative_path(key))
     }
+
+	#[cfg(unix)]
+	fn map_file(&self, file: &File) -> Option<Binary> {
+		if !self.mmap || file.metadata().map(|x| x.len() < MIN_MAPPED_SIZE).unwrap_or(true) {
+			return None;
+		}
+
+		match MappedFile::open(file) {
+			Ok(mapped) => Some(Binary::from_storage(mapped)),
+			Err(error) => {
+				debug!("Could not map cache file, falling back to buffered read: {}", error);
+				None
+			},
+		}
+	}
+
+	#[cfg(not(unix))]
+	fn map_file(&self, _: &File) -> Option<Binary> {
+		None
+	}
 }
 
 impl CacheBackend for FileSystemCache {
-    fn get(&self, key: &String) -> Result<Vec<u8>, KeyError> {
+    fn get(&self, key: &String) -> Result<Binary, KeyError> {
         let final_path = self.get_path(&key);
-     	let mut file = try!{File::open(final_path)};     
+     	let mut file = try!{File::open(final_path)};
+     	if let Some(binary) = self.map_file(&file) {
+     		return Ok(binary);
+     	}
+
      	let mut buffer: Vec<u8> = Vec::new();
      	try!{file.read_to_end(&mut buffer)};
 
-     	Ok(buffer)
+     	Ok(Binary::from(buffer))
     }
 
-    fn put(&mut self, key: &String, payload: &Vec<u8>) -> Result<(), KeyError> {
+    fn put(&mut self, key: &String, payload: &Binary) -> Result<(), KeyError> {
+    	if self.read_only {
+    		return Err(KeyError::ReadOnly);
+    	}
+
+    	// Write to a temporary file and rename it, so the binaries that are currently
+    	// mapped keep pointing to the previous file instead of a truncated one
     	let final_path = self.get_path(&key);
-    	let mut f = try!{File::create(final_path)};
-    	try!{f.write_all(payload)};
+    	let replaced = metadata(&final_path).map(|meta| meta.len()).unwrap_or(0);
+    	try!{Self::create_parent(&final_path)};
+    	try!{Self::write_atomically(&final_path, payload)};
+
+    	if let Some(max_size) = self.max_size {
+    		try!{self.enforce_max_size(max_size, replaced, payload.len() as u64)};
+    	}
 
         Ok(())
     }
-}
\ No newline at end of file
+
+    fn delete(&mut self, key: &String) -> Result<(), KeyError> {
+    	if self.read_only {
+    		return Err(KeyError::ReadOnly);
+    	}
+
+    	let path = self.get_path(&key);
+    	let size = metadata(&path).map(|meta| meta.len()).unwrap_or(0);
+
+    	match remove_file(path) {
+    		Err(ref error) if error.kind() == ErrorKind::NotFound => Err(KeyError::KeyNotFound),
+    		Err(error) => Err(KeyError::from(error)),
+    		Ok(()) => {
+    			self.estimated_size = self.estimated_size.map(|estimated| estimated.saturating_sub(size));
+    			Ok(())
+    		},
+    	}
+    }
+
+    fn is_read_only(&self) -> bool {
+    	self.read_only
+    }
+
+    fn keys(&self) -> Result<Vec<String>, KeyError> {
+    	FileSystemCache::keys(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cache::{CacheBackend, KeyError};
+	use cache::binary::Binary;
+	use std::env;
+	use std::thread;
+	use std::time::Duration;
+
+	/// Returns an empty directory for a test, distinct for every test and test run
+	fn test_dir(name: &str) -> String {
+		let path = env::temp_dir().join(format!("clcache_{}_{}", name, process::id())).to_str().unwrap().to_string();
+		let _ = remove_dir_all(&path);
+
+		path
+	}
+
+	#[test]
+	fn it_does_not_open_a_missing_directory_as_read_only() {
+		let path = test_dir("missing_read_only");
+		assert!(FileSystemCache::read_only(path).is_none());
+	}
+
+	#[test]
+	fn it_serves_hits_but_rejects_puts_when_read_only() {
+		let path = test_dir("read_only");
+		let mut writable = FileSystemCache::new(path.clone()).unwrap();
+		writable.put(&"key".to_string(), &Binary::from(vec![1, 2, 3])).unwrap();
+
+		let mut c = FileSystemCache::read_only(path.clone()).unwrap();
+		assert_eq!(c.get(&"key".to_string()).unwrap(), Binary::from(vec![1, 2, 3]));
+
+		match c.put(&"key".to_string(), &Binary::from(vec![4])) {
+			Err(KeyError::ReadOnly) => (),
+			_ => panic!("Read-only caches should reject puts"),
+		}
+
+		remove_dir_all(&path).unwrap();
+	}
+
+	#[test]
+	fn it_reports_missing_keys_on_delete() {
+		let path = test_dir("delete");
+		let mut c = FileSystemCache::new(path.clone()).unwrap();
+		c.put(&"key".to_string(), &Binary::from(vec![1])).unwrap();
+
+		assert!(c.delete(&"key".to_string()).is_ok());
+		match c.delete(&"key".to_string()) {
+			Err(KeyError::KeyNotFound) => (),
+			other => panic!("Expected KeyNotFound, found {:?}", other),
+		}
+
+		remove_dir_all(&path).unwrap();
+	}
+
+	#[test]
+	fn it_reads_the_same_content_with_and_without_mmap() {
+		let path = test_dir("mmap");
+		let mut c = FileSystemCache::new(path.clone()).unwrap();
+		c.put(&"key".to_string(), &Binary::from(vec![5; 64 * 1024])).unwrap();
+
+		let mapped = c.get(&"key".to_string()).unwrap();
+		c.set_mmap(false);
+		let buffered = c.get(&"key".to_string()).unwrap();
+
+		assert_eq!(mapped, buffered);
+
+		remove_dir_all(&path).unwrap();
+	}
+
+	#[test]
+	fn it_removes_the_oldest_entries_over_the_size_limit() {
+		let path = test_dir("max_size");
+		let mut c = FileSystemCache::new(path.clone()).unwrap();
+
+		c.put(&"old".to_string(), &Binary::from(vec![1; 8])).unwrap();
+		// Entries are ordered by modification time
+		thread::sleep(Duration::from_millis(20));
+		c.set_max_size(Some(10));
+		c.put(&"new".to_string(), &Binary::from(vec![2; 8])).unwrap();
+
+		assert_eq!(c.keys().unwrap(), vec!["new".to_string()]);
+
+		remove_dir_all(&path).unwrap();
+	}
+
+	#[test]
+	fn it_only_shrinks_when_the_estimated_size_is_over_the_limit() {
+		let path = test_dir("estimated_size");
+		let mut c = FileSystemCache::new(path.clone()).unwrap();
+		c.set_max_size(Some(20));
+
+		c.put(&"a".to_string(), &Binary::from(vec![1; 8])).unwrap();
+		c.put(&"a".to_string(), &Binary::from(vec![1; 8])).unwrap();
+		c.put(&"b".to_string(), &Binary::from(vec![2; 8])).unwrap();
+		assert_eq!(c.keys().unwrap().len(), 2);
+
+		thread::sleep(Duration::from_millis(20));
+		c.put(&"c".to_string(), &Binary::from(vec![3; 8])).unwrap();
+		assert_eq!(c.keys().unwrap().len(), 2);
+		assert!(c.get(&"c".to_string()).is_ok());
+
+		remove_dir_all(&path).unwrap();
+	}
+
+	#[test]
+	fn it_migrates_a_flat_directory_to_a_sharded_layout() {
+		let path = test_dir("sharded");
+		let mut flat = FileSystemCache::new(path.clone()).unwrap();
+		flat.put(&"abcdef".to_string(), &Binary::from(vec![1])).unwrap();
+		assert_eq!(flat.layout(), Layout::Flat);
+
+		let sharded = FileSystemCache::with_layout(path.clone(), Layout::sharded()).unwrap();
+		assert_eq!(sharded.get(&"abcdef".to_string()).unwrap(), Binary::from(vec![1]));
+		assert!(metadata(format!("{}/ab/cd/abcdef.clbin", path)).is_ok());
+
+		let reopened = FileSystemCache::new(path.clone()).unwrap();
+		assert_eq!(reopened.layout(), Layout::sharded());
+
+		remove_dir_all(&path).unwrap();
+	}
+}
@@ -0,0 +1,235 @@
+use cache::{CacheBackend, KeyError};
+use cache::binary::Binary;
+use cache::envelope::EntryMetadata;
+use cache::signed::Signer;
+use libc;
+use std::collections::BTreeMap;
+
+/// Entry compiled into an executable. The modules generated by `generate_rust` contain a
+/// static slice of them, which can be served with `EmbeddedBackend`
+pub struct EmbeddedEntry {
+	pub key: &'static str,
+	pub device: &'static str,
+	pub platform_version: &'static str,
+	/// The entry as it was stored on the cache (decompressed), so it's verified like any other one
+	pub binary: &'static [u8],
+}
+
+/// Entry embedded on a C executable (`cl_cache_embedded_entry` on the header). The headers
+/// generated by `generate_c` contain a static array of them
+#[repr(C)]
+pub struct RawEmbeddedEntry {
+	pub key: *const libc::c_char,
+	pub device: *const libc::c_char,
+	pub platform_version: *const libc::c_char,
+	pub binary: *const u8,
+	pub size: libc::size_t,
+}
+
+/// Read-only backend that serves binaries embedded on the executable, without copying them
+pub struct EmbeddedBackend {
+	entries: BTreeMap<String, Binary>,
+}
+
+impl EmbeddedBackend {
+	pub fn new(entries: &'static [EmbeddedEntry]) -> EmbeddedBackend {
+		Self::from_binaries(entries.iter().map(|entry| (entry.key.to_string(), Binary::from_static(entry.binary))).collect())
+	}
+
+	pub fn from_binaries(entries: Vec<(String, Binary)>) -> EmbeddedBackend {
+		EmbeddedBackend {
+			entries: entries.into_iter().collect(),
+		}
+	}
+
+	pub fn len(&self) -> usize {
+		self.entries.len()
+	}
+}
+
+impl CacheBackend for EmbeddedBackend {
+	fn get(&self, key: &String) -> Result<Binary, KeyError> {
+		match self.entries.get(key) {
+			None => Err(KeyError::KeyNotFound),
+			Some(binary) => Ok(binary.clone()),
+		}
+	}
+
+	fn put(&mut self, _: &String, _: &Binary) -> Result<(), KeyError> {
+		Err(KeyError::ReadOnly)
+	}
+
+	fn delete(&mut self, _: &String) -> Result<(), KeyError> {
+		Err(KeyError::ReadOnly)
+	}
+
+	fn is_read_only(&self) -> bool {
+		true
+	}
+
+	fn keys(&self) -> Result<Vec<String>, KeyError> {
+		Ok(self.entries.keys().cloned().collect())
+	}
+}
+
+/// Generates a Rust module with a static `ENTRIES` slice, to be used with `EmbeddedBackend::new`.
+/// The binaries are byte string literals, which rustc parses much faster than arrays
+pub fn generate_rust(entries: &[(String, Binary)]) -> String {
+	let mut module = String::from("// Generated by the clcache embed command. Do not edit\n");
+	module.push_str("use clcache::cache::embedded::EmbeddedEntry;\n\n");
+	module.push_str("pub static ENTRIES: &'static [EmbeddedEntry] = &[\n");
+
+	for &(ref key, ref binary) in entries {
+		let (device, platform_version) = device_and_platform(&binary);
+
+		module.push_str("\tEmbeddedEntry {\n");
+		module.push_str(&format!("\t\tkey: {:?},\n", key));
+		module.push_str(&format!("\t\tdevice: {:?},\n", device));
+		module.push_str(&format!("\t\tplatform_version: {:?},\n", platform_version));
+		module.push_str(&format!("\t\tbinary: {},\n", byte_string(&binary, "\t\t\t")));
+		module.push_str("\t},\n");
+	}
+
+	module.push_str("];\n");
+	module
+}
+
+/// Generates a C header with a static `<name>_entries` array, to be used with `cl_cache_create_embedded`
+pub fn generate_c(entries: &[(String, Binary)], name: &str) -> String {
+	let guard = format!("{}_H", name.to_uppercase());
+	let mut header = format!("/* Generated by the clcache embed command. Do not edit */\n#ifndef {}\n#define {}\n\n", guard, guard);
+	header.push_str("#include \"cl_cache.h\"\n\n");
+
+	for (idx, &(_, ref binary)) in entries.iter().enumerate() {
+		header.push_str(&format!("static const unsigned char {}_binary_{}[] = {{", name, idx));
+		push_bytes(&mut header, &binary, "\t");
+		header.push_str("};\n\n");
+	}
+
+	header.push_str(&format!("static const cl_cache_embedded_entry {}_entries[] = {{\n", name));
+	for (idx, &(ref key, ref binary)) in entries.iter().enumerate() {
+		let (device, platform_version) = device_and_platform(&binary);
+
+		header.push_str(&format!(
+			"\t{{ {}, {}, {}, {}_binary_{}, sizeof({}_binary_{}) }},\n",
+			c_string(key), c_string(&device), c_string(&platform_version), name, idx, name, idx
+		));
+	}
+	header.push_str("};\n\n");
+
+	header.push_str(&format!("#define {}_COUNT {}\n\n#endif\n", name.to_uppercase(), entries.len()));
+	header
+}
+
+/// Device and platform the entry was stored for, or empty strings if it was stored without metadata
+fn device_and_platform(entry: &Binary) -> (String, String) {
+	match EntryMetadata::read(&Signer::strip(&entry)) {
+		Ok((metadata, _)) => (metadata.device_name, metadata.platform_version),
+		Err(_) => (String::new(), String::new()),
+	}
+}
+
+fn push_bytes(output: &mut String, bytes: &[u8], indentation: &str) {
+	for (idx, byte) in bytes.iter().enumerate() {
+		if idx % 16 == 0 {
+			output.push('\n');
+			output.push_str(indentation);
+		}
+
+		output.push_str(&format!("0x{:02x},", byte));
+	}
+
+	output.push('\n');
+}
+
+/// Rust byte string literal, split in lines of 64 bytes. The whitespace after an escaped newline
+/// is not part of the literal, so the spaces are always escaped
+fn byte_string(bytes: &[u8], indentation: &str) -> String {
+	let mut literal = String::from("b\"");
+
+	for (idx, byte) in bytes.iter().enumerate() {
+		if idx % 64 == 0 {
+			literal.push_str("\\\n");
+			literal.push_str(indentation);
+		}
+
+		match *byte {
+			b'"' => literal.push_str("\\\""),
+			b'\\' => literal.push_str("\\\\"),
+			0x21...0x7e => literal.push(*byte as char),
+			_ => literal.push_str(&format!("\\x{:02x}", byte)),
+		}
+	}
+
+	literal.push('"');
+	literal
+}
+
+fn c_string(value: &str) -> String {
+	let mut escaped = String::from("\"");
+
+	for byte in value.bytes() {
+		match byte {
+			b'"' => escaped.push_str("\\\""),
+			b'\\' => escaped.push_str("\\\\"),
+			0x20...0x7e => escaped.push(byte as char),
+			// Octal escapes always have three digits, so they can not absorb the next character
+			_ => escaped.push_str(&format!("\\{:03o}", byte)),
+		}
+	}
+
+	escaped.push('"');
+	escaped
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cache::CacheBackend;
+	use cache::binary::Binary;
+	use cache::envelope::EntryMetadata;
+
+	static ENTRIES: &'static [EmbeddedEntry] = &[
+		EmbeddedEntry {
+			key: "key",
+			device: "GPU",
+			platform_version: "OpenCL 1.2",
+			binary: &[1, 2, 3],
+		},
+	];
+
+	#[test]
+	fn it_serves_the_embedded_binaries_without_copying_them() {
+		let backend = EmbeddedBackend::new(ENTRIES);
+		let binary = backend.get(&"key".to_string()).unwrap();
+
+		assert_eq!(binary.as_ptr(), ENTRIES[0].binary.as_ptr());
+		assert!(backend.get(&"other".to_string()).is_err());
+		assert!(backend.is_read_only());
+	}
+
+	#[test]
+	fn it_generates_the_sources_with_the_metadata() {
+		let metadata = EntryMetadata {
+			device_name: "GPU \"A\"".to_string(),
+			platform_version: "OpenCL 1.2".to_string(),
+			options: String::new(),
+			source_hash: String::new(),
+		};
+		let entries = vec![("key".to_string(), metadata.wrap(&Binary::from(vec![1u8, 2, 3])))];
+
+		let rust = generate_rust(&entries);
+		assert!(rust.contains("device: \"GPU \\\"A\\\"\","));
+		assert!(rust.contains("\\x01\\x02\\x03\","));
+
+		let c = generate_c(&entries, "kernels");
+		assert!(c.contains("{ \"key\", \"GPU \\\"A\\\"\", \"OpenCL 1.2\", kernels_binary_0, sizeof(kernels_binary_0) },"));
+		assert!(c.contains("#define KERNELS_COUNT 1"));
+	}
+
+	#[test]
+	fn it_escapes_the_bytes_of_the_rust_literals() {
+		assert_eq!(byte_string(b"a \"\\\n", ""), "b\"\\\na\\x20\\\"\\\\\\x0a\"");
+		assert_eq!(byte_string(b"", "\t"), "b\"\"");
+	}
+}
@@ -0,0 +1,188 @@
+use cache::CacheError;
+use cache::binary::Binary;
+use cl::device::Device;
+use cl::platform::Platform;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// Every entry stored by a `Cache` starts with this magic, followed by the version of the format
+const MAGIC: &'static [u8] = b"CLCE";
+const VERSION: u8 = 1;
+
+/// Data of the request that stored an entry. It's stored along with the binary and compared
+/// with the request on every hit, so a key collision (or a custom `KeyHasher` that does not take
+/// some input into account) can not load the binary of another program
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntryMetadata {
+	pub device_name: String,
+	pub platform_version: String,
+	pub options: String,
+	/// SHA256 of the source, or of the tag
+	pub source_hash: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum EnvelopeError {
+	/// The entry was stored without metadata, by an older version
+	Legacy,
+	/// The entry has an unknown format version
+	UnsupportedVersion(u8),
+	/// The entry is truncated or its metadata is not valid
+	Corrupted,
+	/// The entry was stored by another request. The field holds the name of the first distinct field
+	Mismatch(&'static str),
+}
+
+impl EntryMetadata {
+	pub fn new(device: &Device, source: &str, options: &str) -> Result<EntryMetadata, CacheError> {
+		let platform = Platform::from_platform_id(try!{device.get_platform_id()});
+		let mut digester = Sha256::new();
+		digester.input_str(source);
+
+		Ok(EntryMetadata {
+			device_name: try!{device.get_name()},
+			platform_version: platform.version(),
+			options: options.to_string(),
+			source_hash: digester.result_str(),
+		})
+	}
+
+	/// Returns the entry to store: the metadata followed by the binary
+	pub fn wrap(&self, binary: &Binary) -> Binary {
+		let mut entry = Vec::with_capacity(binary.len() + 256);
+		entry.extend_from_slice(MAGIC);
+		entry.push(VERSION);
+
+		for field in self.fields().iter() {
+			write_u32(&mut entry, field.len() as u32);
+			entry.extend_from_slice(field.as_bytes());
+		}
+
+		entry.extend_from_slice(&binary);
+
+		Binary::from(entry)
+	}
+
+	/// Returns the binary of the entry if it was stored with the same metadata. The binary is not copied
+	pub fn unwrap(&self, entry: &Binary) -> Result<Binary, EnvelopeError> {
+		let (stored, offset) = try!{Self::read(&entry)};
+
+		let mismatch = self.fields().iter().
+			zip(stored.fields().iter()).
+			zip(FIELD_NAMES.iter()).
+			find(|&((expected, found), _)| expected != found).
+			map(|(_, name)| *name);
+
+		match mismatch {
+			Some(name) => Err(EnvelopeError::Mismatch(name)),
+			None => Ok(entry.skip(offset)),
+		}
+	}
+
+	/// Reads the metadata of an entry. Returns it along with the offset of the binary
+	pub fn read(entry: &[u8]) -> Result<(EntryMetadata, usize), EnvelopeError> {
+		if entry.len() < MAGIC.len() + 1 || &entry[..MAGIC.len()] != MAGIC {
+			return Err(EnvelopeError::Legacy);
+		}
+
+		let version = entry[MAGIC.len()];
+		if version != VERSION {
+			return Err(EnvelopeError::UnsupportedVersion(version));
+		}
+
+		let mut offset = MAGIC.len() + 1;
+		let mut fields = Vec::new();
+		for _ in 0..FIELD_NAMES.len() {
+			let length = try!{read_u32(&entry, offset).ok_or(EnvelopeError::Corrupted)} as usize;
+			offset += 4;
+
+			if entry.len() < offset + length {
+				return Err(EnvelopeError::Corrupted);
+			}
+
+			let field = try!{String::from_utf8(entry[offset..offset + length].to_vec()).map_err(|_| EnvelopeError::Corrupted)};
+			fields.push(field);
+			offset += length;
+		}
+
+		let metadata = EntryMetadata {
+			source_hash: fields.pop().unwrap(),
+			options: fields.pop().unwrap(),
+			platform_version: fields.pop().unwrap(),
+			device_name: fields.pop().unwrap(),
+		};
+
+		Ok((metadata, offset))
+	}
+
+	fn fields(&self) -> [&String; 4] {
+		[&self.device_name, &self.platform_version, &self.options, &self.source_hash]
+	}
+}
+
+const FIELD_NAMES: [&'static str; 4] = ["device name", "platform version", "options", "source hash"];
+
+impl Display for EnvelopeError {
+	fn fmt(&self, f: &mut Formatter) -> FmtResult {
+		match *self {
+			EnvelopeError::Legacy => write!(f, "Entry without metadata"),
+			EnvelopeError::UnsupportedVersion(version) => write!(f, "Unsupported entry version: {}", version),
+			EnvelopeError::Corrupted => write!(f, "Corrupted entry metadata"),
+			EnvelopeError::Mismatch(field) => write!(f, "Entry stored with another {}", field),
+		}
+	}
+}
+
+fn write_u32(buffer: &mut Vec<u8>, value: u32) {
+	for i in 0..4 {
+		buffer.push((value >> (8 * i)) as u8);
+	}
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> Option<u32> {
+	if buffer.len() < offset + 4 {
+		return None;
+	}
+
+	Some((0..4).fold(0, |value, i| value | (buffer[offset + i] as u32) << (8 * i)))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cache::binary::Binary;
+
+	#[test]
+	fn it_returns_the_binary_with_the_same_metadata() {
+		let binary = Binary::from(vec![1u8, 2, 3]);
+		let entry = metadata("-D A=1").wrap(&binary);
+
+		assert_eq!(metadata("-D A=1").unwrap(&entry), Ok(binary));
+	}
+
+	#[test]
+	fn it_rejects_entries_of_other_requests() {
+		let entry = metadata("-D A=1").wrap(&Binary::from(vec![1u8, 2, 3]));
+
+		assert_eq!(metadata("-D A=2").unwrap(&entry), Err(EnvelopeError::Mismatch("options")));
+	}
+
+	#[test]
+	fn it_rejects_legacy_and_truncated_entries() {
+		let entry = metadata("").wrap(&Binary::from(vec![1u8, 2, 3]));
+		let truncated = Binary::from(entry[..10].to_vec());
+
+		assert_eq!(metadata("").unwrap(&Binary::from(vec![1u8, 2, 3])), Err(EnvelopeError::Legacy));
+		assert_eq!(metadata("").unwrap(&truncated), Err(EnvelopeError::Corrupted));
+	}
+
+	fn metadata(options: &str) -> EntryMetadata {
+		EntryMetadata {
+			device_name: "device".to_string(),
+			platform_version: "OpenCL 1.2".to_string(),
+			options: options.to_string(),
+			source_hash: "hash".to_string(),
+		}
+	}
+}
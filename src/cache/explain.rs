@@ -0,0 +1,140 @@
+use cl::device::Device;
+use cl::platform::Platform;
+use cache::CacheError;
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use regex::Regex;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// Inputs of the key of a program for one device, as used by `DefaultHasher`
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyExplanation {
+	/// Key computed by the hasher of the cache
+	pub key: String,
+	/// SHA256 of the source
+	pub source_hash: String,
+	pub device_name: String,
+	pub platform_name: String,
+	pub platform_version: String,
+	/// Options as they are hashed, with the extra options of the cache appended
+	pub options: String,
+	/// Files included by the source. They are not part of the key, so a change on
+	/// them does not cause a miss
+	pub includes: Vec<String>,
+}
+
+/// Field that is distinct on two explanations
+#[derive(Clone, Debug, PartialEq)]
+pub struct KeyDifference {
+	pub field: &'static str,
+	pub left: String,
+	pub right: String,
+}
+
+impl KeyExplanation {
+	pub fn new(key: String, device: &Device, source: &str, options: &str) -> Result<KeyExplanation, CacheError> {
+		let platform = Platform::from_platform_id(try!{device.get_platform_id()});
+		let mut digester = Sha256::new();
+		digester.input_str(source);
+
+		Ok(KeyExplanation {
+			key: key,
+			source_hash: digester.result_str(),
+			device_name: try!{device.get_name()},
+			platform_name: platform.name(),
+			platform_version: platform.version(),
+			options: options.to_string(),
+			includes: find_includes(source),
+		})
+	}
+
+	/// Returns the fields that changed from `self` to `other`. It's empty when both keys have the same inputs
+	pub fn diff(&self, other: &KeyExplanation) -> Vec<KeyDifference> {
+		let fields = [
+			("key", &self.key, &other.key),
+			("source_hash", &self.source_hash, &other.source_hash),
+			("device_name", &self.device_name, &other.device_name),
+			("platform_name", &self.platform_name, &other.platform_name),
+			("platform_version", &self.platform_version, &other.platform_version),
+			("options", &self.options, &other.options),
+		];
+
+		let mut differences: Vec<KeyDifference> = fields.iter().
+			filter(|&&(_, left, right)| left != right).
+			map(|&(field, left, right)| KeyDifference { field: field, left: left.clone(), right: right.clone() }).
+			collect();
+
+		if self.includes != other.includes {
+			differences.push(KeyDifference {
+				field: "includes",
+				left: self.includes.join(", "),
+				right: other.includes.join(", "),
+			});
+		}
+
+		differences
+	}
+}
+
+impl Display for KeyExplanation {
+	fn fmt(&self, f: &mut Formatter) -> FmtResult {
+		try!{writeln!(f, "key: {}", self.key)};
+		try!{writeln!(f, "source_hash: {}", self.source_hash)};
+		try!{writeln!(f, "device_name: {}", self.device_name)};
+		try!{writeln!(f, "platform_name: {}", self.platform_name)};
+		try!{writeln!(f, "platform_version: {}", self.platform_version)};
+		try!{writeln!(f, "options: {}", self.options)};
+		write!(f, "includes (not hashed): {}", self.includes.join(", "))
+	}
+}
+
+impl Display for KeyDifference {
+	fn fmt(&self, f: &mut Formatter) -> FmtResult {
+		write!(f, "{}: {:?} -> {:?}", self.field, self.left, self.right)
+	}
+}
+
+/// Files referenced by the `#include` directives of the source
+pub fn find_includes(source: &str) -> Vec<String> {
+	let regex = Regex::new(r#"(?m)^\s*#\s*include\s*[<"]([^>"]+)[>"]"#).unwrap();
+
+	regex.captures_iter(source).
+		filter_map(|captures| captures.at(1)).
+		map(|include| include.to_string()).
+		collect()
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn it_finds_the_included_files() {
+		let source = "#include \"common.h\"\n  # include <math.cl>\n// #include \"ignored.h\"\n__kernel void a() {}";
+
+		assert_eq!(find_includes(source), vec!["common.h".to_string(), "math.cl".to_string()]);
+	}
+
+	#[test]
+	fn it_only_reports_the_changed_fields() {
+		let left = explanation("-D A=1");
+		let right = explanation("-D A=2");
+
+		let differences = left.diff(&right);
+		assert_eq!(differences.len(), 1);
+		assert_eq!(differences[0].field, "options");
+		assert!(left.diff(&left.clone()).is_empty());
+	}
+
+	fn explanation(options: &str) -> KeyExplanation {
+		KeyExplanation {
+			key: "key".to_string(),
+			source_hash: "hash".to_string(),
+			device_name: "device".to_string(),
+			platform_name: "platform".to_string(),
+			platform_version: "OpenCL 1.2".to_string(),
+			options: options.to_string(),
+			includes: vec![],
+		}
+	}
+}
@@ -0,0 +1,413 @@
+use cache::{CacheBackend, KeyError};
+use cache::binary::Binary;
+use std::io::{Read, Write};
+use std::io::Error as IoError;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+#[derive(Debug)]
+enum RequestError {
+	/// The server could not be reached (connection refused, timeout...)
+	Unreachable(IoError),
+	/// The server answered with something that is not a valid HTTP response
+	InvalidResponse,
+}
+
+struct Response {
+	status: u16,
+	body: Vec<u8>,
+}
+
+/// Backend that stores the binaries on a remote server with a simple key-value HTTP protocol:
+///
+/// * `GET <prefix>/<key>`: Returns the binary (200) or 404 if it does not exist
+/// * `HEAD <prefix>/<key>`: Same as GET, without the binary
+/// * `PUT <prefix>/<key>`: Stores the request body
+///
+/// Every request is retried when the server is unreachable. After all the retries,
+/// the request is forwarded to the fallback backend, if any.
+pub struct RemoteHttpBackend {
+	address: String,
+	prefix: String,
+	timeout: Duration,
+	retries: u32,
+	fallback: Option<Box<CacheBackend>>,
+}
+
+impl RemoteHttpBackend {
+	/// Creates a backend for the server on `address` (`host:port`). All the keys will be
+	/// requested under the `prefix` path (for example, `/clcache`)
+	pub fn new(address: String, prefix: String) -> RemoteHttpBackend {
+		RemoteHttpBackend {
+			address: address,
+			prefix: prefix.trim_right_matches('/').to_string(),
+			timeout: Duration::from_secs(5),
+			retries: 2,
+			fallback: None,
+		}
+	}
+
+	/// Timeout used to connect, read and write on each request
+	pub fn set_timeout(&mut self, timeout: Duration) {
+		self.timeout = timeout;
+	}
+
+	/// Amount of retries when the server is unreachable
+	pub fn set_retries(&mut self, retries: u32) {
+		self.retries = retries;
+	}
+
+	/// Backend used when the server is unreachable
+	pub fn set_fallback(&mut self, fallback: Box<CacheBackend>) {
+		self.fallback = Some(fallback);
+	}
+
+	/// Checks if the key exists on the server without downloading it
+	pub fn contains(&self, key: &String) -> Result<bool, KeyError> {
+		match self.request("HEAD", key, None) {
+			Ok(ref response) if response.status == 200 => Ok(true),
+			Ok(ref response) if response.status == 404 => Ok(false),
+			Ok(response) => Self::unexpected_status(response.status),
+			Err(RequestError::Unreachable(_)) => match self.fallback {
+				None => Err(KeyError::IoError),
+				Some(ref fallback) => Ok(fallback.get(key).is_ok()),
+			},
+			Err(RequestError::InvalidResponse) => Err(KeyError::InvalidContent),
+		}
+	}
+
+	fn request(&self, method: &str, key: &String, body: Option<&[u8]>) -> Result<Response, RequestError> {
+		let mut last_error = None;
+
+		for attempt in 0..(self.retries + 1) {
+			match self.send(method, key, body) {
+				Err(RequestError::Unreachable(error)) => {
+					debug!("Attempt {} to reach {} failed: {}", attempt, self.address, error);
+					last_error = Some(error);
+				},
+				result => return result,
+			}
+		}
+
+		warn!("Remote cache {} is unreachable", self.address);
+		Err(RequestError::Unreachable(last_error.unwrap()))
+	}
+
+	fn send(&self, method: &str, key: &String, body: Option<&[u8]>) -> Result<Response, RequestError> {
+		let mut stream = try!{self.connect().map_err(RequestError::Unreachable)};
+		let body = body.unwrap_or(&[]);
+
+		let header = format!(
+			"{} {}/{} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\nContent-Length: {}\r\n\r\n",
+			method,
+			self.prefix,
+			encode_key(key),
+			self.address,
+			body.len()
+		);
+
+		try!{stream.write_all(header.as_bytes()).map_err(RequestError::Unreachable)};
+		try!{stream.write_all(body).map_err(RequestError::Unreachable)};
+
+		let mut raw_response = Vec::new();
+		try!{stream.read_to_end(&mut raw_response).map_err(RequestError::Unreachable)};
+
+		parse_response(&raw_response, method == "HEAD")
+	}
+
+	fn connect(&self) -> Result<TcpStream, IoError> {
+		let mut last_error = None;
+
+		for address in try!{self.address.to_socket_addrs()} {
+			match TcpStream::connect_timeout(&address, self.timeout) {
+				Ok(stream) => {
+					try!{stream.set_read_timeout(Some(self.timeout))};
+					try!{stream.set_write_timeout(Some(self.timeout))};
+
+					return Ok(stream);
+				},
+				Err(error) => last_error = Some(error),
+			}
+		}
+
+		Err(last_error.unwrap_or(IoError::new(::std::io::ErrorKind::NotFound, "Could not resolve the address")))
+	}
+
+	fn unexpected_status<T>(status: u16) -> Result<T, KeyError> {
+		warn!("Unexpected status code from remote cache: {}", status);
+
+		Err(KeyError::IoError)
+	}
+}
+
+impl CacheBackend for RemoteHttpBackend {
+	fn get(&self, key: &String) -> Result<Binary, KeyError> {
+		match self.request("GET", key, None) {
+			Ok(ref response) if response.status == 404 => Err(KeyError::KeyNotFound),
+			Ok(response) => {
+				if response.status != 200 {
+					return Self::unexpected_status(response.status);
+				}
+
+				Ok(Binary::from(response.body))
+			},
+			Err(RequestError::Unreachable(_)) => match self.fallback {
+				None => Err(KeyError::IoError),
+				Some(ref fallback) => fallback.get(key),
+			},
+			Err(RequestError::InvalidResponse) => Err(KeyError::InvalidContent),
+		}
+	}
+
+	fn put(&mut self, key: &String, payload: &Binary) -> Result<(), KeyError> {
+		match self.request("PUT", key, Some(payload)) {
+			Ok(ref response) if response.status >= 200 && response.status < 300 => Ok(()),
+			Ok(response) => Self::unexpected_status(response.status),
+			Err(RequestError::Unreachable(_)) => match self.fallback {
+				None => Err(KeyError::IoError),
+				Some(ref mut fallback) => fallback.put(key, payload),
+			},
+			Err(RequestError::InvalidResponse) => Err(KeyError::InvalidContent),
+		}
+	}
+}
+
+/// Percent-encodes all the characters that are not unreserved on an URL
+fn encode_key(key: &String) -> String {
+	let mut encoded = String::with_capacity(key.len());
+
+	for byte in key.bytes() {
+		match byte {
+			b'a'...b'z' | b'A'...b'Z' | b'0'...b'9' | b'-' | b'_' | b'.' | b'~' => encoded.push(byte as char),
+			_ => encoded.push_str(&format!("%{:02X}", byte)),
+		}
+	}
+
+	encoded
+}
+
+fn parse_response(raw: &[u8], head: bool) -> Result<Response, RequestError> {
+	let header_end = match raw.windows(4).position(|x| x == b"\r\n\r\n") {
+		None => return Err(RequestError::InvalidResponse),
+		Some(position) => position,
+	};
+
+	let header = String::from_utf8_lossy(&raw[..header_end]);
+	let mut lines = header.lines();
+
+	// Status line: HTTP/1.1 200 OK
+	let status = lines
+		.next()
+		.and_then(|line| line.split_whitespace().nth(1))
+		.and_then(|code| code.parse::<u16>().ok());
+
+	let status = match status {
+		None => return Err(RequestError::InvalidResponse),
+		Some(status) => status,
+	};
+
+	let mut content_length = None;
+	let mut transfer_encoding = None;
+	for line in lines {
+		let mut parts = line.splitn(2, ':');
+		match (parts.next(), parts.next()) {
+			(Some(name), Some(value)) => match &name.trim().to_lowercase()[..] {
+				"content-length" => content_length = value.trim().parse::<usize>().ok(),
+				"transfer-encoding" => transfer_encoding = Some(value.trim().to_lowercase()),
+				_ => (),
+			},
+			_ => (),
+		}
+	}
+
+	let body = &raw[header_end + 4..];
+	let body = match (transfer_encoding, content_length) {
+		_ if head => Vec::new(),
+		// The transfer encoding takes precedence over the length. Only chunked bodies are
+		// supported: anything else would be handed to the driver still encoded
+		(Some(ref encoding), _) if encoding == "chunked" => try!{decode_chunked(body).ok_or(RequestError::InvalidResponse)},
+		(Some(ref encoding), _) => {
+			warn!("Unsupported transfer encoding from remote cache: {}", encoding);
+			return Err(RequestError::InvalidResponse);
+		},
+		(None, Some(length)) if length <= body.len() => body[..length].to_vec(),
+		(None, Some(_)) => return Err(RequestError::InvalidResponse),
+		(None, None) => body.to_vec(),
+	};
+
+	Ok(Response {
+		status: status,
+		body: body,
+	})
+}
+
+/// Decodes a chunked body. Returns `None` if it's malformed or truncated
+fn decode_chunked(mut raw: &[u8]) -> Option<Vec<u8>> {
+	let mut body = Vec::new();
+
+	loop {
+		let line_end = match raw.windows(2).position(|x| x == b"\r\n") {
+			None => return None,
+			Some(position) => position,
+		};
+
+		// Chunk size line: <hex size>[;extensions]
+		let size_line = String::from_utf8_lossy(&raw[..line_end]);
+		let size = match usize::from_str_radix(size_line.split(';').next().unwrap_or("").trim(), 16) {
+			Err(_) => return None,
+			Ok(size) => size,
+		};
+
+		raw = &raw[line_end + 2..];
+		if size == 0 {
+			// The trailers, if any, are ignored
+			return Some(body);
+		}
+
+		if raw.len() < size + 2 || &raw[size..size + 2] != b"\r\n" {
+			return None;
+		}
+
+		body.extend_from_slice(&raw[..size]);
+		raw = &raw[size + 2..];
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cache::{CacheBackend, KeyError};
+	use cache::binary::Binary;
+	use cache::volatile::Volatile;
+	use std::collections::HashMap;
+	use std::io::{BufRead, BufReader, Read, Write};
+	use std::net::{TcpListener, TcpStream};
+	use std::sync::{Arc, Mutex};
+	use std::thread;
+	use std::time::Duration;
+
+	type Storage = Arc<Mutex<HashMap<String, Vec<u8>>>>;
+
+	// Minimal stand-in for a remote cache server
+	fn spawn_server() -> String {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let address = listener.local_addr().unwrap().to_string();
+		let storage: Storage = Arc::new(Mutex::new(HashMap::new()));
+
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				if let Ok(stream) = stream {
+					handle(stream, storage.clone());
+				}
+			}
+		});
+
+		address
+	}
+
+	fn handle(stream: TcpStream, storage: Storage) {
+		let mut reader = BufReader::new(stream);
+		let mut request_line = String::new();
+		reader.read_line(&mut request_line).unwrap();
+		let parts: Vec<String> = request_line.split_whitespace().map(|x| x.to_string()).collect();
+
+		let mut content_length = 0;
+		loop {
+			let mut line = String::new();
+			reader.read_line(&mut line).unwrap();
+			if line.trim().is_empty() {
+				break;
+			}
+
+			if line.to_lowercase().starts_with("content-length:") {
+				content_length = line[15..].trim().parse::<usize>().unwrap();
+			}
+		}
+
+		let mut body = vec![0u8; content_length];
+		reader.read_exact(&mut body).unwrap();
+
+		let mut storage = storage.lock().unwrap();
+		let (status, response_body) = match &parts[0][..] {
+			"PUT" => {
+				storage.insert(parts[1].clone(), body);
+				("201 Created", Vec::new())
+			},
+			_ => match storage.get(&parts[1]) {
+				None => ("404 Not Found", Vec::new()),
+				Some(payload) => ("200 OK", payload.clone()),
+			},
+		};
+
+		let mut stream = reader.into_inner();
+		let header = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n", status, response_body.len());
+		stream.write_all(header.as_bytes()).unwrap();
+		if parts[0] != "HEAD" {
+			stream.write_all(&response_body).unwrap();
+		}
+	}
+
+	fn unreachable_address() -> String {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+		listener.local_addr().unwrap().to_string()
+	}
+
+	#[test]
+	fn it_stores_and_recovers_binaries_from_the_server() {
+		let mut remote = RemoteHttpBackend::new(spawn_server(), "/clcache".to_string());
+		let key = "tag with spaces".to_string();
+
+		assert!(!remote.contains(&key).unwrap());
+		remote.put(&key, &Binary::from(vec![1, 2, 3])).unwrap();
+
+		assert!(remote.contains(&key).unwrap());
+		assert_eq!(remote.get(&key).unwrap(), Binary::from(vec![1, 2, 3]));
+	}
+
+	#[test]
+	fn it_returns_key_not_found_on_missing_keys() {
+		let remote = RemoteHttpBackend::new(spawn_server(), "/clcache".to_string());
+
+		match remote.get(&"missing".to_string()) {
+			Err(KeyError::KeyNotFound) => (),
+			_ => panic!("Missing keys should return KeyNotFound"),
+		}
+	}
+
+	#[test]
+	fn it_decodes_chunked_responses() {
+		let raw = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n3\r\n\x01\x02\x03\r\n2;ext=1\r\n\x04\x05\r\n0\r\n\r\n";
+		let response = parse_response(raw, false).unwrap();
+
+		assert_eq!(response.status, 200);
+		assert_eq!(response.body, vec![1, 2, 3, 4, 5]);
+	}
+
+	#[test]
+	fn it_rejects_truncated_chunks_and_unknown_transfer_encodings() {
+		let truncated = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n5\r\n\x01\x02";
+		let gzip = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: gzip\r\n\r\n\x1f\x8b";
+
+		match parse_response(truncated, false) {
+			Err(RequestError::InvalidResponse) => (),
+			_ => panic!("Truncated chunks should be rejected"),
+		}
+
+		match parse_response(gzip, false) {
+			Err(RequestError::InvalidResponse) => (),
+			_ => panic!("Unsupported transfer encodings should be rejected"),
+		}
+	}
+
+	#[test]
+	fn it_uses_the_fallback_when_the_server_is_unreachable() {
+		let mut remote = RemoteHttpBackend::new(unreachable_address(), "/clcache".to_string());
+		remote.set_retries(1);
+		remote.set_timeout(Duration::from_millis(200));
+		remote.set_fallback(Box::new(Volatile::new()));
+
+		remote.put(&"key".to_string(), &Binary::from(vec![4])).unwrap();
+		assert_eq!(remote.get(&"key".to_string()).unwrap(), Binary::from(vec![4]));
+	}
+}
@@ -0,0 +1,323 @@
+use cache::{CacheBackend, KeyError};
+use cache::binary::Binary;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::mem;
+use std::ops::Drop;
+
+/// Amount of pending writes kept by default before the write-back layers are flushed
+pub const DEFAULT_MAX_PENDING: usize = 256;
+
+/// Describes how the writes reach a layer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum WritePolicy {
+	/// Writes are stored on the layer immediately
+	WriteThrough,
+	/// Writes are kept in memory until `flush` is called, there are too many of them (see
+	/// `LayeredBackend::set_max_pending`) or the backend is dropped
+	WriteBack,
+	/// The layer is never written (it will only serve hits)
+	Skip,
+}
+
+struct Layer {
+	backend: RefCell<Box<CacheBackend>>,
+	policy: WritePolicy,
+}
+
+/// Backend built over an ordered list of backends, from the fastest to the slowest.
+/// Reads fall through the layers and the hits are promoted to the faster ones.
+pub struct LayeredBackend {
+	layers: Vec<Layer>,
+	// Pending writes of the write-back layers, indexed by layer and key
+	pending: RefCell<BTreeMap<(usize, String), Binary>>,
+	max_pending: usize,
+}
+
+impl LayeredBackend {
+	pub fn new() -> LayeredBackend {
+		LayeredBackend {
+			layers: Vec::new(),
+			pending: RefCell::new(BTreeMap::new()),
+			max_pending: DEFAULT_MAX_PENDING,
+		}
+	}
+
+	/// Sets the amount of pending writes (`DEFAULT_MAX_PENDING` by default) that forces a flush
+	pub fn set_max_pending(&mut self, max_pending: usize) {
+		self.max_pending = max_pending;
+	}
+
+	/// Adds a layer below all the previous ones
+	pub fn push(&mut self, backend: Box<CacheBackend>, policy: WritePolicy) {
+		self.layers.push(Layer {
+			backend: RefCell::new(backend),
+			policy: policy,
+		});
+	}
+
+	pub fn len(&self) -> usize {
+		self.layers.len()
+	}
+
+	/// Stores all the pending writes of the write-back layers
+	pub fn flush(&mut self) -> Result<(), KeyError> {
+		self.flush_pending()
+	}
+
+	fn flush_pending(&self) -> Result<(), KeyError> {
+		let pending = mem::replace(&mut *self.pending.borrow_mut(), BTreeMap::new());
+		let mut result = Ok(());
+
+		for ((idx, key), payload) in pending {
+			if let Err(error) = self.layers[idx].backend.borrow_mut().put(&key, &payload) {
+				warn!("Could not flush key {} to layer {}: {:?}", key, idx, error);
+				result = Err(error);
+			}
+		}
+
+		result
+	}
+
+	fn lookup(&self, idx: usize, key: &String) -> Result<Binary, KeyError> {
+		let pending = self.pending.borrow().get(&(idx, key.clone())).cloned();
+
+		match pending {
+			Some(payload) => Ok(payload),
+			None => self.layers[idx].backend.borrow().get(key),
+		}
+	}
+
+	fn write_layer(&self, idx: usize, key: &String, payload: &Binary) -> Result<bool, KeyError> {
+		let layer = &self.layers[idx];
+		if layer.backend.borrow().is_read_only() {
+			return Ok(false);
+		}
+
+		match layer.policy {
+			WritePolicy::Skip => Ok(false),
+			WritePolicy::WriteThrough => {
+				try!{layer.backend.borrow_mut().put(key, payload)};
+				Ok(true)
+			},
+			WritePolicy::WriteBack => {
+				let pending = {
+					let mut pending = self.pending.borrow_mut();
+					pending.insert((idx, key.clone()), payload.clone());
+					pending.len()
+				};
+
+				if pending > self.max_pending {
+					debug!("Flushing {} pending writes", pending);
+					// The errors are already logged, and the write was accepted
+					let _ = self.flush_pending();
+				}
+
+				Ok(true)
+			},
+		}
+	}
+
+	fn promote(&self, hit_layer: usize, key: &String, payload: &Binary) {
+		for idx in 0..hit_layer {
+			if let Err(error) = self.write_layer(idx, key, payload) {
+				warn!("Could not promote key {} to layer {}: {:?}", key, idx, error);
+			}
+		}
+	}
+}
+
+impl CacheBackend for LayeredBackend {
+	fn get(&self, key: &String) -> Result<Binary, KeyError> {
+		for idx in 0..self.layers.len() {
+			if let Ok(payload) = self.lookup(idx, key) {
+				debug!("Key {} found on layer {}", key, idx);
+				self.promote(idx, key, &payload);
+
+				return Ok(payload);
+			}
+		}
+
+		Err(KeyError::KeyNotFound)
+	}
+
+	/// Writes the binary on all the layers, even if some of them fail. The first error is returned
+	fn put(&mut self, key: &String, payload: &Binary) -> Result<(), KeyError> {
+		let mut stored = false;
+		let mut failure = None;
+
+		for idx in 0..self.layers.len() {
+			match self.write_layer(idx, key, payload) {
+				Ok(written) => stored |= written,
+				Err(error) => {
+					warn!("Could not store key {} on layer {}: {:?}", key, idx, error);
+					failure = failure.or(Some(error));
+				},
+			}
+		}
+
+		match (failure, stored) {
+			(Some(error), _) => Err(error),
+			(None, true) => Ok(()),
+			(None, false) => Err(KeyError::ReadOnly),
+		}
+	}
+
+	fn delete(&mut self, key: &String) -> Result<(), KeyError> {
+		for idx in 0..self.layers.len() {
+			self.pending.borrow_mut().remove(&(idx, key.clone()));
+		}
+		let mut deleted = false;
+
+		for layer in self.layers.iter() {
+			if layer.policy == WritePolicy::Skip {
+				continue;
+			}
+
+			match layer.backend.borrow_mut().delete(key) {
+				Ok(_) => deleted = true,
+				Err(KeyError::KeyNotFound) | Err(KeyError::ReadOnly) => (),
+				Err(error) => return Err(error),
+			}
+		}
+
+		if deleted {
+			Ok(())
+		} else {
+			Err(KeyError::KeyNotFound)
+		}
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.layers.iter().all(|layer| {
+			layer.policy == WritePolicy::Skip || layer.backend.borrow().is_read_only()
+		})
+	}
+
+	/// Returns the keys stored on any layer. The layers that can not list their keys are skipped
+	fn keys(&self) -> Result<Vec<String>, KeyError> {
+		let mut keys: Vec<String> = self.pending.borrow().keys().map(|&(_, ref key)| key.clone()).collect();
+		let mut listed = false;
+
+		for layer in self.layers.iter() {
+			match layer.backend.borrow().keys() {
+				Ok(layer_keys) => {
+					keys.extend(layer_keys);
+					listed = true;
+				},
+				Err(KeyError::Unsupported) => (),
+				Err(error) => return Err(error),
+			}
+		}
+
+		if !listed {
+			return Err(KeyError::Unsupported);
+		}
+
+		keys.sort();
+		keys.dedup();
+
+		Ok(keys)
+	}
+}
+
+impl Drop for LayeredBackend {
+	fn drop(&mut self) {
+		let _ = self.flush();
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cache::{CacheBackend, KeyError};
+	use cache::volatile::{SharedBackend, Volatile};
+	use cache::binary::Binary;
+	use std::cell::RefCell;
+	use std::rc::Rc;
+
+	#[test]
+	fn it_promotes_hits_to_the_faster_layers() {
+		let fast = Rc::new(RefCell::new(Volatile::new()));
+		let slow = Rc::new(RefCell::new(Volatile::new()));
+		slow.borrow_mut().put(&"key".to_string(), &Binary::from(vec![1, 2])).unwrap();
+
+		let mut layered = LayeredBackend::new();
+		layered.push(Box::new(SharedBackend(fast.clone())), WritePolicy::WriteThrough);
+		layered.push(Box::new(SharedBackend(slow.clone())), WritePolicy::Skip);
+
+		assert_eq!(layered.get(&"key".to_string()).unwrap(), Binary::from(vec![1, 2]));
+		assert_eq!(fast.borrow().get(&"key".to_string()).unwrap(), Binary::from(vec![1, 2]));
+	}
+
+	#[test]
+	fn it_delays_write_back_layers_until_flush() {
+		let fast = Rc::new(RefCell::new(Volatile::new()));
+		let slow = Rc::new(RefCell::new(Volatile::new()));
+
+		let mut layered = LayeredBackend::new();
+		layered.push(Box::new(SharedBackend(fast.clone())), WritePolicy::WriteThrough);
+		layered.push(Box::new(SharedBackend(slow.clone())), WritePolicy::WriteBack);
+
+		layered.put(&"key".to_string(), &Binary::from(vec![3])).unwrap();
+		assert!(slow.borrow().get(&"key".to_string()).is_err());
+
+		layered.flush().unwrap();
+		assert_eq!(slow.borrow().get(&"key".to_string()).unwrap(), Binary::from(vec![3]));
+	}
+
+	#[test]
+	fn it_flushes_when_there_are_too_many_pending_writes() {
+		let slow = Rc::new(RefCell::new(Volatile::new()));
+
+		let mut layered = LayeredBackend::new();
+		layered.push(Box::new(SharedBackend(slow.clone())), WritePolicy::WriteBack);
+		layered.set_max_pending(1);
+
+		// Repeated writes of the same key are only stored once
+		layered.put(&"a".to_string(), &Binary::from(vec![1])).unwrap();
+		layered.put(&"a".to_string(), &Binary::from(vec![2])).unwrap();
+		assert!(slow.borrow().keys().unwrap().is_empty());
+		assert_eq!(layered.get(&"a".to_string()).unwrap(), Binary::from(vec![2]));
+
+		layered.put(&"b".to_string(), &Binary::from(vec![3])).unwrap();
+		assert_eq!(slow.borrow().get(&"a".to_string()).unwrap(), Binary::from(vec![2]));
+		assert_eq!(slow.borrow().get(&"b".to_string()).unwrap(), Binary::from(vec![3]));
+	}
+
+	#[test]
+	fn it_writes_all_the_layers_when_one_of_them_fails() {
+		let slow = Rc::new(RefCell::new(Volatile::new()));
+
+		let mut layered = LayeredBackend::new();
+		layered.push(Box::new(FailingBackend), WritePolicy::WriteThrough);
+		layered.push(Box::new(SharedBackend(slow.clone())), WritePolicy::WriteThrough);
+
+		assert!(layered.put(&"key".to_string(), &Binary::from(vec![3])).is_err());
+		assert_eq!(slow.borrow().get(&"key".to_string()).unwrap(), Binary::from(vec![3]));
+	}
+
+	#[test]
+	fn it_is_read_only_if_no_layer_can_be_written() {
+		let mut layered = LayeredBackend::new();
+		layered.push(Box::new(Volatile::new()), WritePolicy::Skip);
+
+		assert!(layered.is_read_only());
+		match layered.put(&"key".to_string(), &Binary::from(vec![3])) {
+			Err(KeyError::ReadOnly) => (),
+			_ => panic!("Skipped layers should not be written"),
+		}
+	}
+
+	struct FailingBackend;
+
+	impl CacheBackend for FailingBackend {
+		fn get(&self, _: &String) -> Result<Binary, KeyError> {
+			Err(KeyError::KeyNotFound)
+		}
+
+		fn put(&mut self, _: &String, _: &Binary) -> Result<(), KeyError> {
+			Err(KeyError::IoError)
+		}
+	}
+}
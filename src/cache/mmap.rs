@@ -0,0 +1,68 @@
+use libc;
+use std::fs::File;
+use std::io::{Error, ErrorKind, Result};
+use std::os::unix::io::AsRawFd;
+use std::ptr;
+use std::slice;
+use std::ops::Drop;
+
+/// Read-only memory mapping of a whole file
+pub struct MappedFile {
+	ptr: *mut libc::c_void,
+	len: usize,
+}
+
+// The mapping is private and read-only, so it can be shared between threads
+unsafe impl Send for MappedFile {}
+unsafe impl Sync for MappedFile {}
+
+impl MappedFile {
+	pub fn open(file: &File) -> Result<MappedFile> {
+		let len = try!{file.metadata()}.len() as usize;
+
+		// Empty files can not be mapped
+		if len == 0 {
+			return Err(Error::new(ErrorKind::InvalidInput, "Can not map an empty file"));
+		}
+
+		let ptr = unsafe {
+			libc::mmap(
+				ptr::null_mut(),
+				len as libc::size_t,
+				libc::PROT_READ,
+				libc::MAP_PRIVATE,
+				file.as_raw_fd(),
+				0
+			)
+		};
+
+		if ptr == libc::MAP_FAILED {
+			return Err(Error::last_os_error());
+		}
+
+		Ok(MappedFile {
+			ptr: ptr,
+			len: len,
+		})
+	}
+
+	pub fn len(&self) -> usize {
+		self.len
+	}
+}
+
+impl AsRef<[u8]> for MappedFile {
+	fn as_ref(&self) -> &[u8] {
+		unsafe {
+			slice::from_raw_parts(self.ptr as *const u8, self.len)
+		}
+	}
+}
+
+impl Drop for MappedFile {
+	fn drop(&mut self) {
+		unsafe {
+			libc::munmap(self.ptr, self.len as libc::size_t);
+		}
+	}
+}
@@ -1,5 +1,20 @@
 pub mod volatile;
 pub mod disk;
+pub mod layered;
+pub mod pack;
+pub mod sqlite;
+pub mod http;
+pub mod redis;
+pub mod callback;
+pub mod compressed;
+pub mod embedded;
+pub mod binary;
+pub mod bundle;
+pub mod envelope;
+pub mod signed;
+pub mod explain;
+#[cfg(unix)]
+pub mod mmap;
 
 use cl::device::Device;
 use cl::context::Context;
@@ -11,17 +26,126 @@ use crypto::digest::Digest;
 use crypto::sha2::Sha256;
 use std::rc::Rc;
 use std::io::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use self::binary::Binary;
+use self::explain::KeyExplanation;
+use self::envelope::{EntryMetadata, EnvelopeError};
+use self::signed::Signer;
+use config::EnvConfig;
+
+/// Counters of the operations done by a `Cache`. Hits and misses are counted per device.
+/// It's also returned as is through the C API, so the field order is part of it
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub compilations: u64,
+    pub stores: u64,
+    pub invalidations: u64,
+    /// Entries rejected because their signature was not valid
+    pub rejections: u64,
+}
+
+/// Amount of programs kept by default to serve the repeated requests of the same context
+pub const DEFAULT_MAX_PROGRAMS: usize = 64;
 
 pub struct Cache {
     backend: Box<CacheBackend>,
     key_hasher: Box<KeyHasher>,
+    stats: CacheStats,
+    disabled: bool,
+    read_only: bool,
+    extra_options: String,
+    signer: Option<Signer>,
+    // Programs already loaded, indexed by their context and the keys of all their devices, with
+    // the tick of their last use. The least recently used one is released when it's full
+    programs: HashMap<(usize, String), (Program, u64)>,
+    programs_tick: u64,
+    max_programs: usize,
 }
 
 impl Cache {
     pub fn new(backend: Box<CacheBackend>) -> Cache {
+        Self::with_key_hasher(backend, Box::new(DefaultHasher::new()))
+    }
+
+    /// Creates a cache that computes the keys with a custom hasher. The entries are verified
+    /// on every hit, so a hasher that ignores some input causes misses, never wrong programs
+    pub fn with_key_hasher(backend: Box<CacheBackend>, key_hasher: Box<KeyHasher>) -> Cache {
         Cache {
             backend: backend,
-            key_hasher: Box::new(DefaultHasher::new()),
+            key_hasher: key_hasher,
+            stats: CacheStats::default(),
+            disabled: false,
+            read_only: false,
+            extra_options: String::new(),
+            signer: None,
+            programs: HashMap::new(),
+            programs_tick: 0,
+            max_programs: DEFAULT_MAX_PROGRAMS,
+        }
+    }
+
+    /// Creates the cache configured by the `CLCACHE_*` environment variables (see `config::EnvConfig`)
+    pub fn from_env() -> Result<Cache, CacheError> {
+        EnvConfig::from_env().create_cache()
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        self.stats
+    }
+
+    /// A disabled cache builds all the programs from source without reading or storing any binary
+    pub fn set_disabled(&mut self, disabled: bool) {
+        self.disabled = disabled;
+    }
+
+    /// A read-only cache serves hits, but the built programs are not stored
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only || self.backend.is_read_only()
+    }
+
+    /// Options appended to the options of every build. They are also part of the keys
+    pub fn set_extra_options(&mut self, extra_options: &str) {
+        self.extra_options = extra_options.trim().to_string();
+    }
+
+    /// Signs the stored entries with HMAC-SHA256 and the given secret. Once it's set, the entries
+    /// without a valid signature are rejected (and counted on `rejections`) before they reach the
+    /// driver, and the programs are built again. `None` disables the signatures
+    pub fn set_signing_key(&mut self, secret: Option<&[u8]>) {
+        self.signer = secret.map(Signer::new);
+        // The loaded programs may not pass the new checks
+        self.clear_programs();
+    }
+
+    /// Releases the programs kept to serve the repeated requests of the same context. Each one
+    /// retains its context, so they should be released before the contexts are
+    pub fn clear_programs(&mut self) {
+        self.programs.clear();
+    }
+
+    /// Sets the amount of programs kept to serve the repeated requests of the same context
+    /// (`DEFAULT_MAX_PROGRAMS` by default). 0 disables it
+    pub fn set_max_programs(&mut self, max_programs: usize) {
+        self.max_programs = max_programs;
+
+        while self.programs.len() > max_programs {
+            self.release_lru_program();
+        }
+    }
+
+    /// Returns the options that will be used to build a program requested with `options`
+    pub fn build_options(&self, options: &str) -> String {
+        match (options.len(), self.extra_options.len()) {
+            (_, 0) => options.to_string(),
+            (0, _) => self.extra_options.clone(),
+            _ => format!("{} {}", options, self.extra_options),
         }
     }
 
@@ -30,43 +154,308 @@ impl Cache {
     }
 
     pub fn get_with_tag(&mut self, tag: &str, devices: &Vec<Rc<Device>>, ctx: &Context) -> Result<Program, CacheError> {
-        let mut binaries: Vec<Vec<u8>> = Vec::new();
+        self.get_with_tag_and_options(&tag, &devices, &ctx, "")
+    }
+
+    /// Same as `get_with_tag`, but only the binaries that were stored with the same options are used.
+    /// The programs of the tags are never built by the cache, so the extra options are not appended
+    pub fn get_with_tag_and_options(&mut self, tag: &str, devices: &Vec<Rc<Device>>, ctx: &Context, options: &str) -> Result<Program, CacheError> {
+        let mut keys = Vec::new();
+        let mut metadata = Vec::new();
+        for device in devices {
+            keys.push(try!{self.key_hasher.get_tag_key(&device, &tag, &options)});
+            metadata.push(try!{EntryMetadata::new(&device, &tag, &options)});
+        }
+
+        self.load_program(&keys, &metadata, &devices, &ctx)
+    }
 
+    /// Loads the program of the source and options only if the binaries of all the devices are
+    /// on the cache. Unlike `get_with_options`, the program is never built from the source
+    pub fn get_cached_with_options(&mut self, source: &str, devices: &Vec<Rc<Device>>, ctx: &Context, options: &str) -> Result<Program, CacheError> {
+        let options = self.build_options(options);
+        let mut keys = Vec::new();
+        let mut metadata = Vec::new();
         for device in devices {
-            let key = try!{self.key_hasher.get_tag_key(&device, &tag)};
-            let cache_result = self.backend.get(&key);
+            keys.push(try!{self.key_hasher.get_key(&device, &source.to_string(), &options.to_string())});
+            metadata.push(try!{EntryMetadata::new(&device, &source, &options)});
+        }
+
+        self.load_program(&keys, &metadata, &devices, &ctx)
+    }
+
+    /// Checks if the binaries of the source and options are on the cache for all the devices,
+    /// without loading the program
+    pub fn contains_with_options(&mut self, source: &str, devices: &Vec<Rc<Device>>, options: &str) -> Result<bool, CacheError> {
+        if self.disabled {
+            return Ok(false);
+        }
+
+        let options = self.build_options(options);
+        for device in devices {
+            let key = try!{self.key_hasher.get_key(&device, &source.to_string(), &options)};
+            let metadata = try!{EntryMetadata::new(&device, &source, &options)};
+
+            if self.read_entry(&key, &metadata).is_none() {
+                return Ok(false);
+            }
+        }
+
+        Ok(true)
+    }
+
+    /// Returns the inputs of the key of each device, on the same order as `devices`. Two
+    /// explanations can be compared with `KeyExplanation::diff` to find why a key changed
+    pub fn explain_key(&mut self, source: &str, devices: &Vec<Rc<Device>>, options: &str) -> Result<Vec<KeyExplanation>, CacheError> {
+        let options = self.build_options(options);
+        let mut explanations = Vec::new();
+        for device in devices {
+            let key = try!{self.key_hasher.get_key(&device, &source.to_string(), &options)};
+            explanations.push(try!{KeyExplanation::new(key, &device, &source, &options)});
+        }
+
+        Ok(explanations)
+    }
+
+    fn load_program(&mut self, keys: &Vec<String>, metadata: &Vec<EntryMetadata>, devices: &Vec<Rc<Device>>, ctx: &Context) -> Result<Program, CacheError> {
+        if self.disabled {
+            return Err(CacheError::NotAllBinariesLoaded(devices.clone()));
+        }
+
+        if let Some(program) = self.memoized_program(&keys, &ctx) {
+            return Ok(program);
+        }
+
+        let mut binaries: Vec<Binary> = Vec::new();
+
+        for ((key, metadata), device) in keys.iter().zip(metadata.iter()).zip(devices.iter()) {
+            let cache_result = self.read_entry(&key, &metadata);
 
             match cache_result {
-                Err(_) => {
+                None => {
                     info!("Program not found for device: {:?}", device);
+                    self.stats.misses += 1;
                     return Err(CacheError::NotAllBinariesLoaded(devices.clone()));
                 },
-                Ok(binary) => {
+                Some(binary) => {
                     info!("Program found on cache for device: {:?}", device);
+                    self.stats.hits += 1;
                     binaries.push(binary);
                 },
             }
         }
 
-        self.get_program_from_binaries(&ctx, &devices, &binaries)
+        let program = try!{self.get_program_from_binaries(&ctx, &devices, &binaries)};
+        self.memoize(&keys, &ctx, &program);
+
+        Ok(program)
+    }
+
+    /// Returns a new reference to the program that was loaded for the same keys on the context.
+    /// The memoized programs retain their context, so its id can not be reused while they exist
+    fn memoized_program(&mut self, keys: &Vec<String>, ctx: &Context) -> Option<Program> {
+        self.programs_tick += 1;
+        let tick = self.programs_tick;
+
+        let program = self.programs.get_mut(&memo_key(&keys, &ctx)).map(|&mut (ref program, ref mut last_use)| {
+            *last_use = tick;
+            Program::from_cl_program(program.get_id())
+        });
+        if program.is_some() {
+            self.stats.hits += keys.len() as u64;
+        }
+
+        program
+    }
+
+    fn memoize(&mut self, keys: &Vec<String>, ctx: &Context, program: &Program) {
+        if self.max_programs == 0 {
+            return;
+        }
+
+        let memo_key = memo_key(&keys, &ctx);
+        if !self.programs.contains_key(&memo_key) && self.programs.len() >= self.max_programs {
+            self.release_lru_program();
+        }
+
+        self.programs_tick += 1;
+        self.programs.insert(memo_key, (Program::from_cl_program(program.get_id()), self.programs_tick));
+    }
+
+    fn release_lru_program(&mut self) {
+        let lru = self.programs.iter().min_by_key(|&(_, &(_, last_use))| last_use).map(|(memo_key, _)| memo_key.clone());
+
+        if let Some(memo_key) = lru {
+            debug!("Releasing the least recently used program of the context {}", memo_key.0);
+            self.programs.remove(&memo_key);
+        }
+    }
+
+    /// Releases the memoized programs that use any of the keys, as their binaries are replaced or removed
+    fn forget_programs(&mut self, keys: &Vec<String>) {
+        let stale: Vec<(usize, String)> = self.programs.keys().
+            filter(|&&(_, ref joined)| joined.split('\n').any(|memo_key| keys.iter().any(|key| key == memo_key))).
+            cloned().
+            collect();
+
+        for memo_key in stale {
+            self.programs.remove(&memo_key);
+        }
+    }
+
+    /// Returns the binary stored on the key, only if it was stored by a request with the same
+    /// metadata. Entries of other requests are misses. The ones stored without metadata, by the
+    /// versions before the envelope, are loaded one last time and stored again with the metadata
+    fn read_entry(&mut self, key: &String, metadata: &EntryMetadata) -> Option<Binary> {
+        let entry = match self.backend.get(&key) {
+            Err(_) => return None,
+            Ok(entry) => entry,
+        };
+
+        // The signature is checked before anything else is read from the entry
+        let entry = match self.signer {
+            None => entry,
+            Some(ref signer) => match signer.verify(&key, &entry) {
+                Ok(binary) => binary,
+                Err(error) => {
+                    warn!("Rejected the entry of key {}: {}", key, error);
+                    self.stats.rejections += 1;
+                    return None;
+                },
+            },
+        };
+
+        match metadata.unwrap(&entry) {
+            Ok(binary) => Some(binary),
+            Err(EnvelopeError::Legacy) => {
+                info!("Migrating the entry of key {}, stored without metadata", key);
+                if !self.disabled && !self.is_read_only() {
+                    let sealed = self.seal_entry(&key, &metadata, &entry);
+                    if let Err(error) = self.backend.put(&key, &sealed) {
+                        warn!("Could not migrate the entry of key {}: {:?}", key, error);
+                    }
+                }
+                Some(entry)
+            },
+            Err(error) => {
+                warn!("Ignoring the entry of key {}: {}", key, error);
+                None
+            },
+        }
+    }
+
+    /// Returns the entry to store for a binary: the binary with its metadata, signed if there is a signing key
+    fn seal_entry(&self, key: &String, metadata: &EntryMetadata, binary: &Binary) -> Binary {
+        let entry = metadata.wrap(&binary);
+
+        match self.signer {
+            None => entry,
+            Some(ref signer) => signer.sign(&key, &entry),
+        }
     }
 
     pub fn put_with_tag(&mut self, tag: &str, devices: &Vec<Rc<Device>>, program: &Program) -> Result<(), CacheError> {
-        let binaries = try!{program.get_binaries()};
+        self.put_with_tag_and_options(&tag, &devices, &program, "")
+    }
+
+    /// Same as `put_with_tag`, but the binaries are stored for the options the program was built with.
+    /// The extra options are not appended, as the caller built the program without them
+    pub fn put_with_tag_and_options(&mut self, tag: &str, devices: &Vec<Rc<Device>>, program: &Program, options: &str) -> Result<(), CacheError> {
+        let mut keys = Vec::new();
+        let mut metadata = Vec::new();
+        for device in devices {
+            keys.push(try!{self.key_hasher.get_tag_key(&device, &tag, &options)});
+            metadata.push(try!{EntryMetadata::new(&device, &tag, &options)});
+        }
+
+        self.store_program(&keys, &metadata, &devices, &program)
+    }
+
+    /// Stores the binaries of a program that was already built from the source with the options.
+    /// The options are used as they are: the extra options are not appended, as the caller built
+    /// the program without them, so the entry is only a hit for a `get_with_options` that builds
+    /// with the same options
+    pub fn put_with_options(&mut self, source: &str, devices: &Vec<Rc<Device>>, program: &Program, options: &str) -> Result<(), CacheError> {
+        let mut keys = Vec::new();
+        let mut metadata = Vec::new();
+        for device in devices {
+            keys.push(try!{self.key_hasher.get_key(&device, &source.to_string(), &options.to_string())});
+            metadata.push(try!{EntryMetadata::new(&device, &source, &options)});
+        }
+
+        self.store_program(&keys, &metadata, &devices, &program)
+    }
+
+    fn store_program(&mut self, keys: &Vec<String>, metadata: &Vec<EntryMetadata>, devices: &Vec<Rc<Device>>, program: &Program) -> Result<(), CacheError> {
+        if self.disabled {
+            return Ok(());
+        }
+
+        if self.is_read_only() {
+            return Err(CacheError::ReadOnly);
+        }
+
+        self.forget_programs(&keys);
+
+        let binaries: Vec<Binary> = try!{program.get_binaries()}.into_iter().map(Binary::from).collect();
         for (idx, b) in binaries.iter().enumerate() {
             if b.len() == 0 {
                 return Err(CacheError::NeedBinaryProgram(devices[idx].clone()))
             }
         }
 
-        for (idx, d) in devices.iter().enumerate() {
-            let put_result = self.backend.put(
-                &try!{self.key_hasher.get_tag_key(&d, &tag)},
-                &binaries[idx]
-            );
+        let entries: Vec<(String, Binary)> = keys.iter().
+            zip(metadata.iter().zip(binaries.iter())).
+            map(|(key, (metadata, binary))| (key.clone(), self.seal_entry(&key, &metadata, &binary))).
+            collect();
+
+        if let Err(_) = self.backend.put_all(&entries) {
+            return Err(CacheError::CacheError);
+        }
+        self.stats.stores += entries.len() as u64;
+
+        Ok(())
+    }
+
+    /// Removes the binaries of the given source and options for all the devices
+    pub fn invalidate(&mut self, source: &str, devices: &Vec<Rc<Device>>, options: &str) -> Result<(), CacheError> {
+        let options = self.build_options(options);
+        let mut keys = Vec::new();
+        for device in devices {
+            keys.push(try!{self.key_hasher.get_key(&device, &source.to_string(), &options.to_string())});
+        }
+
+        self.delete_keys(&keys)
+    }
+
+    /// Removes the binaries stored with the given tag and options (without the extra options, like
+    /// `put_with_tag_and_options`) for all the devices
+    pub fn invalidate_tag(&mut self, tag: &str, devices: &Vec<Rc<Device>>, options: &str) -> Result<(), CacheError> {
+        let mut keys = Vec::new();
+        for device in devices {
+            keys.push(try!{self.key_hasher.get_tag_key(&device, &tag, &options)});
+        }
+
+        self.delete_keys(&keys)
+    }
+
+    fn delete_keys(&mut self, keys: &Vec<String>) -> Result<(), CacheError> {
+        if self.disabled {
+            return Ok(());
+        }
+
+        if self.read_only {
+            return Err(CacheError::ReadOnly);
+        }
+
+        self.forget_programs(&keys);
 
-            if let Err(_) = put_result {
-                return Err(CacheError::CacheError);
+        for key in keys {
+            match self.backend.delete(key) {
+                Ok(_) => self.stats.invalidations += 1,
+                Err(KeyError::KeyNotFound) => (),
+                Err(KeyError::ReadOnly) => return Err(CacheError::ReadOnly),
+                Err(_) => return Err(CacheError::CacheError),
             }
         }
 
@@ -74,30 +463,54 @@ impl Cache {
     }
 
     pub fn get_with_options(&mut self, source: &str, devices: &Vec<Rc<Device>>, ctx: &Context, options: &str) -> Result<Program, CacheError> {
-        // TODO: Avoid all this clones by creating smarter data structures
-        // The code is copying a lot of times the buffers with the binaries
-        let source_str = source.to_string();
-        let mut binaries_hash: HashMap<Rc<Device>, Vec<u8>>  = HashMap::new();
-        let mut non_build_devices = Vec::new();
+        self.get_with_sources(&[source], &devices, &ctx, &options)
+    }
+
+    /// Same as `get_with_options`, but the source is split on several fragments, like on
+    /// `clCreateProgramWithSource`. The fragments are hashed as a single string, so the
+    /// binaries are shared with the programs that provide the same source on one fragment
+    pub fn get_with_sources(&mut self, sources: &[&str], devices: &Vec<Rc<Device>>, ctx: &Context, options: &str) -> Result<Program, CacheError> {
+        let options = self.build_options(options);
+        if self.disabled {
+            return self.build_program(&sources, &options, &ctx, &devices);
+        }
+
         let mut keys = Vec::new();
+        let mut metadata = Vec::new();
+        let source = sources.concat();
 
         for device in devices {
-            let key = try!{self.key_hasher.get_key(&device, &source_str, &options.to_string())};
+            keys.push(try!{self.key_hasher.get_sources_key(&device, &sources, &options.to_string())});
+            metadata.push(try!{EntryMetadata::new(&device, &source, &options)});
+        }
+
+        if let Some(program) = self.memoized_program(&keys, &ctx) {
+            return Ok(program);
+        }
 
-            let cache_result = self.backend.get(&key);
+        let mut binaries_hash: HashMap<Rc<Device>, Binary>  = HashMap::new();
+        let mut non_build_devices = Vec::new();
+        let mut missing_keys = Vec::new();
+        let mut missing_metadata = Vec::new();
+
+        for ((key, device_metadata), device) in keys.iter().zip(metadata.into_iter()).zip(devices.iter()) {
+            let cache_result = self.read_entry(&key, &device_metadata);
             match cache_result {
-                Err(_) => {
+                None => {
+                    self.stats.misses += 1;
                     non_build_devices.push(device.clone());
-                    keys.push(key.clone());
+                    missing_keys.push(key.clone());
+                    missing_metadata.push(device_metadata);
                 },
-                Ok(binary) => {
+                Some(binary) => {
+                    self.stats.hits += 1;
                     binaries_hash.insert(device.clone(), binary);
                 },
             }
         }
 
         if non_build_devices.len() > 0 {
-            try!{self.compile_program(&mut binaries_hash, &source, &options, &ctx, &non_build_devices, &keys)};
+            try!{self.compile_program(&mut binaries_hash, &sources, &options, &ctx, &non_build_devices, &missing_keys, &missing_metadata)};
         }
 
         let mut final_binaries = Vec::new();
@@ -105,10 +518,13 @@ impl Cache {
             final_binaries.push(binaries_hash[device].clone());
         }
 
-        self.get_program_from_binaries(&ctx, &devices, &final_binaries)
+        let program = try!{self.get_program_from_binaries(&ctx, &devices, &final_binaries)};
+        self.memoize(&keys, &ctx, &program);
+
+        Ok(program)
     }
 
-    fn get_program_from_binaries(&self, ctx: &Context, devices: &Vec<Rc<Device>>, binaries: &Vec<Vec<u8>>) -> Result<Program, CacheError> {
+    fn get_program_from_binaries(&self, ctx: &Context, devices: &Vec<Rc<Device>>, binaries: &Vec<Binary>) -> Result<Program, CacheError> {
         let program = Program::from_binary(ctx, devices, &binaries);
         match program  {
             Err(cl_error) => {
@@ -122,32 +538,50 @@ impl Cache {
         }
     }
 
-    fn compile_program(&mut self, binaries_hash: &mut HashMap<Rc<Device>, Vec<u8>>, source: &str, options: &str, ctx: &Context, devices: &Vec<Rc<Device>>, keys: &Vec<String>) -> Result<(), CacheError> {
-        let program = try!{Program::from_source(ctx, source)};
+    fn build_program(&mut self, sources: &[&str], options: &str, ctx: &Context, devices: &Vec<Rc<Device>>) -> Result<Program, CacheError> {
+        let program = try!{Program::from_sources(ctx, sources)};
+        self.stats.compilations += 1;
         let build_result = if options.len() > 0 {
             program.build_with_options(&devices, &options)
         } else {
             program.build(&devices)
         };
-        
+
         if build_result.is_err() {
             return Err(CacheError::ClBuildError(self.get_build_logs(&program, &devices)));
         }
 
+        Ok(program)
+    }
+
+    fn compile_program(&mut self, binaries_hash: &mut HashMap<Rc<Device>, Binary>, sources: &[&str], options: &str, ctx: &Context, devices: &Vec<Rc<Device>>, keys: &Vec<String>, metadata: &Vec<EntryMetadata>) -> Result<(), CacheError> {
+        let program = try!{self.build_program(&sources, &options, &ctx, &devices)};
+
         let binaries = try!{program.get_binaries()};
 
         if binaries.iter().any(|x| x.len() == 0) {
             return Err(CacheError::ClBuildError(self.get_build_logs(&program, &devices)));   
         }
 
-        for (idx, device) in devices.iter().enumerate() {
-            let binary = binaries[idx].clone();
-            let put_result = self.backend.put(&keys[idx], &binary);
+        let binaries: Vec<Binary> = binaries.into_iter().map(Binary::from).collect();
+        let entries: Vec<(String, Binary)> = keys.iter().
+            zip(metadata.iter().zip(binaries.iter())).
+            map(|(key, (metadata, binary))| (key.clone(), self.seal_entry(&key, &metadata, &binary))).
+            collect();
+
+        self.forget_programs(&keys);
 
-            if let Err(_) = put_result {
-                return Err(CacheError::CacheError);
+        if self.read_only {
+            info!("Read-only cache: Skipping store for devices: {:?}", devices);
+        } else {
+            match self.backend.put_all(&entries) {
+                Err(KeyError::ReadOnly) => info!("Read-only cache: Skipping store for devices: {:?}", devices),
+                Err(_) => return Err(CacheError::CacheError),
+                Ok(_) => self.stats.stores += entries.len() as u64,
             }
+        }
 
+        for (device, binary) in devices.iter().zip(binaries.into_iter()) {
             binaries_hash.insert(device.clone(), binary);
         }
 
@@ -167,6 +601,10 @@ impl Cache {
     }
 }
 
+fn memo_key(keys: &Vec<String>, ctx: &Context) -> (usize, String) {
+    (ctx.get_id() as usize, keys.join("\n"))
+}
+
 #[derive(Debug)]
 pub enum CacheError {
     ClBuildError(HashMap<Rc<Device>, String>),
@@ -174,9 +612,24 @@ pub enum CacheError {
     NotAllBinariesLoaded(Vec<Rc<Device>>),
     NeedBinaryProgram(Rc<Device>),
     CacheError,
+    ReadOnly,
     IoError(Error),
 }
 
+impl Display for CacheError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match *self {
+            CacheError::ClBuildError(ref logs) => write!(f, "Program could not be built for {} device(s)", logs.len()),
+            CacheError::ClError(ref error) => write!(f, "OpenCL error: {}", error),
+            CacheError::NotAllBinariesLoaded(ref devices) => write!(f, "Not all the binaries were found on the cache ({} device(s) requested)", devices.len()),
+            CacheError::NeedBinaryProgram(ref device) => write!(f, "Program has no binary for device: {:?}", device),
+            CacheError::CacheError => write!(f, "Cache backend error"),
+            CacheError::ReadOnly => write!(f, "Cache is read-only"),
+            CacheError::IoError(ref error) => write!(f, "IO error: {}", error),
+        }
+    }
+}
+
 impl From<OpenClError> for CacheError {
     fn from(error: OpenClError) -> Self {
         CacheError::ClError(error)
@@ -194,6 +647,8 @@ pub enum KeyError {
     IoError,
     KeyNotFound,
     InvalidContent,
+    ReadOnly,
+    Unsupported,
 }
 
 impl From<Error> for KeyError {
@@ -204,13 +659,44 @@ impl From<Error> for KeyError {
 
 
 pub trait CacheBackend {
-    fn get(&self, key: &String) -> Result<Vec<u8>, KeyError>;
-    fn put(&mut self, key: &String, payload: &Vec<u8>) -> Result<(), KeyError>;
+    fn get(&self, key: &String) -> Result<Binary, KeyError>;
+    fn put(&mut self, key: &String, payload: &Binary) -> Result<(), KeyError>;
+
+    /// Stores the binaries of several entries (for example, the ones of all the devices of a program).
+    /// Backends with transactions should store all of them or none
+    fn put_all(&mut self, entries: &[(String, Binary)]) -> Result<(), KeyError> {
+        for &(ref key, ref payload) in entries {
+            try!{self.put(key, payload)};
+        }
+
+        Ok(())
+    }
+
+    /// Removes the entry. Backends that can not remove entries return `KeyError::Unsupported`
+    fn delete(&mut self, _: &String) -> Result<(), KeyError> {
+        Err(KeyError::Unsupported)
+    }
+
+    /// Read-only backends only serve hits: `put` will always fail with `KeyError::ReadOnly`
+    fn is_read_only(&self) -> bool {
+        false
+    }
+
+    /// Returns the keys of all the stored entries. Backends that can not list their entries
+    /// return `KeyError::Unsupported`
+    fn keys(&self) -> Result<Vec<String>, KeyError> {
+        Err(KeyError::Unsupported)
+    }
 }
 
 pub trait KeyHasher {
     fn get_key(&mut self, device: &Device, source: &String, options: &String) -> Result<String, CacheError>;
-    fn get_tag_key(&mut self, device: &Device, tag: &str) -> Result<String, CacheError>;
+    fn get_tag_key(&mut self, device: &Device, tag: &str, options: &str) -> Result<String, CacheError>;
+
+    /// Key of a source split on several fragments. By default, it's the key of the concatenated source
+    fn get_sources_key(&mut self, device: &Device, sources: &[&str], options: &String) -> Result<String, CacheError> {
+        self.get_key(device, &sources.concat(), options)
+    }
 }
 
 pub struct DefaultHasher {
@@ -239,14 +725,14 @@ impl KeyHasher for DefaultHasher {
         Ok(self.digester.result_str())
     }
 
-    fn get_tag_key(&mut self, device: &Device, tag: &str) -> Result<String, CacheError> {
+    fn get_tag_key(&mut self, device: &Device, tag: &str, options: &str) -> Result<String, CacheError> {
         self.digester.reset();
         let device_name = try!{device.get_name()};
         let platform_id = try!{device.get_platform_id()};
         let platform = Platform::from_platform_id(platform_id);
         let platform_name = platform.name();
         let platform_version = platform.version();
-        let content_to_hash = "".to_string() + &(*device_name) + &(*platform_name) + &(*platform_version);
+        let content_to_hash = "".to_string() + &(*device_name) + &(*platform_name) + &(*platform_version) + options;
         self.digester.input_str(&content_to_hash);
 
         Ok(tag.to_string().clone() + &self.digester.result_str())
@@ -259,19 +745,25 @@ pub mod test {
     use cl::context::Context;
     use cl::program::Program;
     use cl::device::Device;
-    use cache::volatile::Volatile;
+    use cache::volatile::{SharedBackend, Volatile};
+    use cache::disk::FileSystemCache;
+    use cache::binary::Binary;
     use cl::cl_root::*;
     use cl::platform::*;
+    use std::cell::RefCell;
+    use std::env;
+    use std::fs::remove_dir_all;
+    use std::process;
     use std::rc::Rc;
 
     struct DummyCacheBackend;
 
     impl CacheBackend for DummyCacheBackend {
-        fn get(&self, _: &String) -> Result<Vec<u8>, KeyError> {
+        fn get(&self, _: &String) -> Result<Binary, KeyError> {
             Err(KeyError::KeyNotFound)
         }
 
-        fn put(&mut self, _: &String, _: &Vec<u8>) -> Result<(), KeyError> {
+        fn put(&mut self, _: &String, _: &Binary) -> Result<(), KeyError> {
             Ok(())
         }
     }
@@ -356,6 +848,185 @@ pub mod test {
         }
     }
 
+    #[test]
+    fn it_stores_the_programs_with_the_options_they_were_built_with() {
+        let mut c = create_cache_volatile_backend();
+        c.set_extra_options("-D EXTRA=1");
+        let src = get_demo_source();
+        let (ctx, devices) = get_context();
+        let prg = Program::from_source(&ctx, &src).unwrap();
+        prg.build_with_options(&devices, "-D A=1").unwrap();
+
+        c.put_with_options(&src, &devices, &prg, "-D A=1").unwrap();
+        assert!(!c.contains_with_options(&src, &devices, "-D A=1").unwrap());
+
+        c.set_extra_options("");
+        assert!(c.contains_with_options(&src, &devices, "-D A=1").unwrap());
+    }
+
+    #[test]
+    fn it_uses_the_tags_with_the_options_of_the_caller() {
+        let mut c = create_cache_volatile_backend();
+        c.set_extra_options("-D EXTRA=1");
+        let src = get_demo_source();
+        let (ctx, devices) = get_context();
+        let prg = Program::from_source(&ctx, &src).unwrap();
+        prg.build_with_options(&devices, "-D A=1").unwrap();
+
+        c.put_with_tag_and_options("test", &devices, &prg, "-D A=1").unwrap();
+        assert!(c.get_with_tag_and_options("test", &devices, &ctx, "-D A=1").is_ok());
+
+        c.invalidate_tag("test", &devices, "-D A=1").unwrap();
+        assert!(c.stats().invalidations > 0);
+        assert!(c.get_with_tag_and_options("test", &devices, &ctx, "-D A=1").is_err());
+    }
+
+    #[test]
+    fn it_creates_the_same_key_for_split_sources() {
+        let (_, devices) = get_context();
+        let mut hasher = DefaultHasher::new();
+
+        let device = &devices[0];
+        let whole = hasher.get_key(&device, &"__kernel void a() {}".to_string(), &"".to_string()).unwrap();
+        let split = hasher.get_sources_key(&device, &["__kernel ", "void a() {}"], &"".to_string()).unwrap();
+
+        assert_eq!(whole, split);
+    }
+
+    #[test]
+    fn it_creates_distinct_tag_keys_with_distinct_options() {
+        let (_, devices) = get_context();
+        let mut hasher = DefaultHasher::new();
+
+        let device = &devices[0];
+        let key_wo_options = hasher.get_tag_key(&device, "test", "").unwrap();
+        let key_with_options = hasher.get_tag_key(&device, "test", "-D test=2").unwrap();
+
+        assert!(key_wo_options != key_with_options)
+    }
+
+    #[test]
+    fn it_does_not_load_the_binaries_of_a_colliding_key() {
+        let mut c = Cache::with_key_hasher(Box::new(Volatile::new()), Box::new(CollidingHasher));
+        let src = get_demo_source();
+        let (ctx, devices) = get_context();
+
+        c.get_with_options(&src, &devices, &ctx, "-D A=1").unwrap();
+        c.get_with_options(&src, &devices, &ctx, "-D A=2").unwrap();
+
+        let stats = c.stats();
+        assert_eq!(stats.hits, 0);
+        assert_eq!(stats.compilations, 2);
+    }
+
+    #[test]
+    fn it_rejects_and_rebuilds_the_unsigned_entries() {
+        let storage = Rc::new(RefCell::new(Volatile::new()));
+        let mut unsigned = Cache::new(Box::new(SharedBackend(storage.clone())));
+        let mut signed = Cache::new(Box::new(SharedBackend(storage.clone())));
+        signed.set_signing_key(Some(&b"secret"[..]));
+        let src = get_demo_source();
+        let (ctx, devices) = get_context();
+
+        unsigned.get(&src, &devices, &ctx).unwrap();
+        signed.get(&src, &devices, &ctx).unwrap();
+        signed.get(&src, &devices, &ctx).unwrap();
+
+        let stats = signed.stats();
+        assert_eq!(stats.rejections, devices.len() as u64);
+        assert_eq!(stats.compilations, 1);
+        assert_eq!(stats.hits, devices.len() as u64);
+    }
+
+    #[test]
+    fn it_migrates_the_entries_stored_without_metadata() {
+        let storage = Rc::new(RefCell::new(Volatile::new()));
+        let mut c = Cache::new(Box::new(SharedBackend(storage.clone())));
+        let src = get_demo_source();
+        let (ctx, devices) = get_context();
+
+        c.get(&src, &devices, &ctx).unwrap();
+        let keys = storage.borrow().keys().unwrap();
+        for key in keys.iter() {
+            let entry = storage.borrow().get(key).unwrap();
+            let (_, offset) = EntryMetadata::read(&entry).unwrap();
+            storage.borrow_mut().put(key, &entry.skip(offset)).unwrap();
+        }
+
+        let mut upgraded = Cache::new(Box::new(SharedBackend(storage.clone())));
+        upgraded.get(&src, &devices, &ctx).unwrap();
+
+        let stats = upgraded.stats();
+        assert_eq!(stats.hits, devices.len() as u64);
+        assert_eq!(stats.compilations, 0);
+        for key in keys.iter() {
+            assert_eq!(&storage.borrow().get(key).unwrap()[..4], b"CLCE");
+        }
+    }
+
+    #[test]
+    fn it_returns_the_same_program_to_repeated_requests_of_a_context() {
+        let mut c = create_cache_volatile_backend();
+        let src = get_demo_source();
+        let (ctx, devices) = get_context();
+
+        let first = c.get(&src, &devices, &ctx).unwrap();
+        let second = c.get(&src, &devices, &ctx).unwrap();
+        assert_eq!(first.get_id(), second.get_id());
+        assert_eq!(c.stats().hits, devices.len() as u64);
+
+        c.invalidate(&src, &devices, "").unwrap();
+        let rebuilt = c.get(&src, &devices, &ctx).unwrap();
+        assert!(rebuilt.get_id() != first.get_id());
+        assert_eq!(c.stats().compilations, 2);
+    }
+
+    #[test]
+    fn it_releases_the_least_recently_used_programs() {
+        let mut c = create_cache_volatile_backend();
+        c.set_max_programs(1);
+        let src = get_demo_source();
+        let (ctx, devices) = get_context();
+
+        let first = c.get(&src, &devices, &ctx).unwrap();
+        c.get_with_options(&src, &devices, &ctx, "-D other=1").unwrap();
+        let reloaded = c.get(&src, &devices, &ctx).unwrap();
+        assert!(reloaded.get_id() != first.get_id());
+        assert_eq!(c.stats().compilations, 2);
+
+        let memoized = c.get(&src, &devices, &ctx).unwrap();
+        assert_eq!(memoized.get_id(), reloaded.get_id());
+    }
+
+    #[test]
+    fn it_invalidates_the_keys_missing_on_a_file_system_cache() {
+        let path = env::temp_dir().join(format!("clcache_invalidate_missing_{}", process::id())).to_str().unwrap().to_string();
+        let _ = remove_dir_all(&path);
+        let mut c = Cache::new(Box::new(FileSystemCache::new(path.clone()).unwrap()));
+        let src = get_demo_source();
+        let (_, devices) = get_context();
+
+        c.invalidate(&src, &devices, "-D never_stored=1").unwrap();
+        c.invalidate_tag("never_stored", &devices, "").unwrap();
+        assert_eq!(c.stats().invalidations, 0);
+
+        remove_dir_all(&path).unwrap();
+    }
+
+    #[test]
+    fn it_explains_which_input_changed_the_key() {
+        let mut c = create_cache_dummy_backend();
+        let src = get_demo_source();
+        let (_, devices) = get_context();
+
+        let without_options = c.explain_key(&src, &devices, "").unwrap();
+        let with_options = c.explain_key(&src, &devices, "-D test=2").unwrap();
+        assert_eq!(without_options.len(), devices.len());
+
+        let fields: Vec<&str> = without_options[0].diff(&with_options[0]).iter().map(|x| x.field).collect();
+        assert_eq!(fields, vec!["key", "options"]);
+    }
+
     #[test]
     fn it_can_not_cache_same_program_with_distinct_options_and_same_tag() {
         /*let mut c = create_cache_volatile_backend();
@@ -369,6 +1040,19 @@ pub mod test {
         // TODO: Assert binaries are distinct
     }
 
+    /// Hasher that returns the same key for all the requests
+    struct CollidingHasher;
+
+    impl KeyHasher for CollidingHasher {
+        fn get_key(&mut self, _: &Device, _: &String, _: &String) -> Result<String, CacheError> {
+            Ok("key".to_string())
+        }
+
+        fn get_tag_key(&mut self, _: &Device, _: &str, _: &str) -> Result<String, CacheError> {
+            Ok("key".to_string())
+        }
+    }
+
     fn create_cache_dummy_backend() -> Cache {
         Cache::new(Box::new(DummyCacheBackend))
     }
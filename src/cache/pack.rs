@@ -0,0 +1,493 @@
+use cache::{CacheBackend, KeyError};
+use cache::binary::Binary;
+#[cfg(unix)]
+use cache::mmap::MappedFile;
+#[cfg(unix)]
+use libc;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions, rename};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::io::Result as IoResult;
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
+#[cfg(unix)]
+use std::sync::Arc;
+
+const PACK_MAGIC: &'static [u8] = b"CLPACK";
+const PACK_VERSION: u32 = 1;
+const HEADER_SIZE: u64 = 10;
+
+const RECORD_PUT: u8 = 1;
+const RECORD_DELETE: u8 = 2;
+
+#[derive(Copy, Clone, Debug)]
+struct IndexEntry {
+	offset: u64,
+	len: u64,
+}
+
+/// Backend that stores all the entries on a single append-only file.
+///
+/// Each record contains a kind (put or delete), the key and the payload. The index
+/// is rebuilt on opening by skipping over the payloads, and `compact` rewrites the
+/// pack with only the live entries, so a whole cache can be shipped as a single file.
+///
+/// A pack has a single writer: on unix, `open` takes an exclusive lock on the file, so it
+/// fails while another process (or another `PackBackend`) has it open for writing. The
+/// read-only packs do not take it.
+pub struct PackBackend {
+	path: String,
+	file: RefCell<File>,
+	index: HashMap<String, IndexEntry>,
+	end: u64,
+	dead_bytes: u64,
+	read_only: bool,
+	#[cfg(unix)]
+	mapped: Option<Arc<MappedFile>>,
+}
+
+#[cfg(unix)]
+struct PackSlice {
+	map: Arc<MappedFile>,
+	offset: usize,
+	len: usize,
+}
+
+#[cfg(unix)]
+impl AsRef<[u8]> for PackSlice {
+	fn as_ref(&self) -> &[u8] {
+		let data: &[u8] = (*self.map).as_ref();
+		&data[self.offset..self.offset + self.len]
+	}
+}
+
+impl PackBackend {
+	/// Opens the pack on the given path, creating it if it does not exist. It fails if the pack
+	/// is already open for writing
+	pub fn open(path: String) -> Result<PackBackend, KeyError> {
+		let mut file = try!{OpenOptions::new().read(true).write(true).create(true).open(&path)};
+
+		if try!{file.metadata()}.len() == 0 {
+			try!{Self::write_header(&mut file)};
+		}
+
+		Self::from_file(path, file, false)
+	}
+
+	/// Opens an existing pack without ever writing on it. On unix, the pack is
+	/// mapped on memory and the binaries are served without copying them
+	pub fn open_read_only(path: String) -> Result<PackBackend, KeyError> {
+		let file = try!{File::open(&path)};
+
+		Self::from_file(path, file, true)
+	}
+
+	/// Amount of live entries
+	pub fn len(&self) -> usize {
+		self.index.len()
+	}
+
+	/// Bytes used by the records of replaced or deleted entries (and by the delete records),
+	/// which will be freed on `compact`
+	pub fn dead_bytes(&self) -> u64 {
+		self.dead_bytes
+	}
+
+	pub fn keys(&self) -> Vec<String> {
+		self.index.keys().cloned().collect()
+	}
+
+	/// Rewrites the pack keeping only the live entries
+	pub fn compact(&mut self) -> Result<(), KeyError> {
+		if self.read_only {
+			return Err(KeyError::ReadOnly);
+		}
+
+		let tmp_path = format!("{}.compact", self.path);
+		{
+			let mut compacted = try!{File::create(&tmp_path)};
+			try!{Self::write_header(&mut compacted)};
+
+			let mut keys = self.keys();
+			keys.sort();
+
+			for key in keys.iter() {
+				let payload = try!{self.get(key)};
+				try!{Self::write_record(&mut compacted, RECORD_PUT, key, &payload)};
+			}
+		}
+
+		try!{rename(&tmp_path, &self.path)};
+		let file = try!{OpenOptions::new().read(true).write(true).open(&self.path)};
+		*self = try!{Self::from_file(self.path.clone(), file, false)};
+
+		Ok(())
+	}
+
+	fn from_file(path: String, file: File, read_only: bool) -> Result<PackBackend, KeyError> {
+		let mut pack = PackBackend {
+			path: path,
+			file: RefCell::new(file),
+			index: HashMap::new(),
+			end: HEADER_SIZE,
+			dead_bytes: 0,
+			read_only: read_only,
+			#[cfg(unix)]
+			mapped: None,
+		};
+
+		if !read_only {
+			try!{lock_for_writing(&pack.file.borrow())};
+		}
+
+		try!{pack.load_index()};
+		if read_only && pack.end > HEADER_SIZE {
+			pack.map_file();
+		}
+
+		Ok(pack)
+	}
+
+	#[cfg(unix)]
+	fn map_file(&mut self) {
+		self.mapped = MappedFile::open(&self.file.borrow()).ok().map(Arc::new);
+	}
+
+	#[cfg(not(unix))]
+	fn map_file(&mut self) {
+	}
+
+	fn load_index(&mut self) -> Result<(), KeyError> {
+		let mut file = self.file.borrow_mut();
+		let file_size = try!{file.metadata()}.len();
+
+		try!{file.seek(SeekFrom::Start(0))};
+		let mut magic = [0u8; 6];
+		try!{file.read_exact(&mut magic)};
+		if &magic[..] != PACK_MAGIC || try!{read_u32(&mut *file)} != PACK_VERSION {
+			return Err(KeyError::InvalidContent);
+		}
+
+		let mut offset = HEADER_SIZE;
+		while offset < file_size {
+			// Truncated records (for example, an interrupted write) are ignored, whether they
+			// were cut inside the header or inside the payload
+			let (kind, key, payload_len) = match read_record_header(&mut *file, file_size - offset) {
+				Ok(header) => header,
+				Err(ref error) if error.kind() == ErrorKind::UnexpectedEof => {
+					warn!("Truncated record found on pack {}", self.path);
+					break;
+				},
+				Err(error) => return Err(KeyError::from(error)),
+			};
+
+			let payload_offset = offset + 1 + 4 + key.len() as u64 + 8;
+			if payload_offset + payload_len > file_size {
+				warn!("Truncated record found on pack {}", self.path);
+				break;
+			}
+
+			let key = try!{String::from_utf8(key).map_err(|_| KeyError::InvalidContent)};
+			let key_len = key.len();
+			let previous = match kind {
+				RECORD_PUT => self.index.insert(key, IndexEntry { offset: payload_offset, len: payload_len }),
+				RECORD_DELETE => {
+					self.dead_bytes += record_size(key_len, payload_len);
+					self.index.remove(&key)
+				},
+				_ => return Err(KeyError::InvalidContent),
+			};
+
+			if let Some(previous) = previous {
+				self.dead_bytes += record_size(key_len, previous.len);
+			}
+
+			offset = try!{file.seek(SeekFrom::Start(payload_offset + payload_len))};
+		}
+
+		self.end = offset;
+
+		Ok(())
+	}
+
+	fn append(&mut self, kind: u8, key: &String, payload: &[u8]) -> Result<u64, KeyError> {
+		if self.read_only {
+			return Err(KeyError::ReadOnly);
+		}
+
+		let mut file = self.file.borrow_mut();
+		try!{file.seek(SeekFrom::Start(self.end))};
+		try!{Self::write_record(&mut *file, kind, key, payload)};
+
+		let payload_offset = self.end + 1 + 4 + key.len() as u64 + 8;
+		self.end = payload_offset + payload.len() as u64;
+
+		Ok(payload_offset)
+	}
+
+	fn write_header(file: &mut File) -> IoResult<()> {
+		try!{file.write_all(PACK_MAGIC)};
+		write_u32(file, PACK_VERSION)
+	}
+
+	fn write_record<W: Write>(writer: &mut W, kind: u8, key: &String, payload: &[u8]) -> IoResult<()> {
+		try!{writer.write_all(&[kind])};
+		try!{write_u32(writer, key.len() as u32)};
+		try!{writer.write_all(key.as_bytes())};
+		try!{write_u64(writer, payload.len() as u64)};
+		writer.write_all(payload)
+	}
+
+	#[cfg(unix)]
+	fn get_mapped(&self, entry: &IndexEntry) -> Option<Binary> {
+		self.mapped.as_ref().map(|map| {
+			Binary::from_storage(PackSlice {
+				map: map.clone(),
+				offset: entry.offset as usize,
+				len: entry.len as usize,
+			})
+		})
+	}
+
+	#[cfg(not(unix))]
+	fn get_mapped(&self, _: &IndexEntry) -> Option<Binary> {
+		None
+	}
+}
+
+impl CacheBackend for PackBackend {
+	fn get(&self, key: &String) -> Result<Binary, KeyError> {
+		let entry = match self.index.get(key) {
+			None => return Err(KeyError::KeyNotFound),
+			Some(entry) => *entry,
+		};
+
+		if let Some(binary) = self.get_mapped(&entry) {
+			return Ok(binary);
+		}
+
+		let mut file = self.file.borrow_mut();
+		try!{file.seek(SeekFrom::Start(entry.offset))};
+		let mut buffer = vec![0u8; entry.len as usize];
+		try!{file.read_exact(&mut buffer)};
+
+		Ok(Binary::from(buffer))
+	}
+
+	fn put(&mut self, key: &String, payload: &Binary) -> Result<(), KeyError> {
+		let offset = try!{self.append(RECORD_PUT, key, payload)};
+		let previous = self.index.insert(key.clone(), IndexEntry { offset: offset, len: payload.len() as u64 });
+
+		if let Some(previous) = previous {
+			self.dead_bytes += record_size(key.len(), previous.len);
+		}
+
+		Ok(())
+	}
+
+	fn delete(&mut self, key: &String) -> Result<(), KeyError> {
+		if !self.index.contains_key(key) {
+			return Err(KeyError::KeyNotFound);
+		}
+
+		try!{self.append(RECORD_DELETE, key, &[])};
+		// Both the deleted record and the delete record are dead
+		if let Some(previous) = self.index.remove(key) {
+			self.dead_bytes += record_size(key.len(), previous.len) + record_size(key.len(), 0);
+		}
+
+		Ok(())
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+
+	fn keys(&self) -> Result<Vec<String>, KeyError> {
+		Ok(PackBackend::keys(self))
+	}
+}
+
+/// Size of a record: kind, key length, key, payload length and payload
+fn record_size(key_len: usize, payload_len: u64) -> u64 {
+	1 + 4 + key_len as u64 + 8 + payload_len
+}
+
+#[cfg(unix)]
+fn lock_for_writing(file: &File) -> Result<(), KeyError> {
+	// The lock is released when the file is closed
+	if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+		warn!("The pack is already open for writing: {}", Error::last_os_error());
+		return Err(KeyError::IoError);
+	}
+
+	Ok(())
+}
+
+#[cfg(not(unix))]
+fn lock_for_writing(_: &File) -> Result<(), KeyError> {
+	Ok(())
+}
+
+fn write_u32<W: Write>(writer: &mut W, value: u32) -> IoResult<()> {
+	let bytes = [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8];
+	writer.write_all(&bytes)
+}
+
+fn write_u64<W: Write>(writer: &mut W, value: u64) -> IoResult<()> {
+	try!{write_u32(writer, value as u32)};
+	write_u32(writer, (value >> 32) as u32)
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> IoResult<u32> {
+	let mut bytes = [0u8; 4];
+	try!{reader.read_exact(&mut bytes)};
+
+	Ok(bytes.iter().rev().fold(0u32, |acc, &b| (acc << 8) | b as u32))
+}
+
+fn read_u64<R: Read>(reader: &mut R) -> IoResult<u64> {
+	let low = try!{read_u32(reader)} as u64;
+	let high = try!{read_u32(reader)} as u64;
+
+	Ok((high << 32) | low)
+}
+
+/// Reads the kind, the key and the payload length of a record. Fails with `UnexpectedEof`
+/// if the header does not fit on the `remaining` bytes of the file
+fn read_record_header<R: Read>(reader: &mut R, remaining: u64) -> IoResult<(u8, Vec<u8>, u64)> {
+	let mut kind = [0u8; 1];
+	try!{reader.read_exact(&mut kind)};
+
+	let key_len = try!{read_u32(reader)} as u64;
+	if 1 + 4 + key_len + 8 > remaining {
+		return Err(Error::new(ErrorKind::UnexpectedEof, "Truncated record header"));
+	}
+
+	let mut key = vec![0u8; key_len as usize];
+	try!{reader.read_exact(&mut key)};
+	let payload_len = try!{read_u64(reader)};
+
+	Ok((kind[0], key, payload_len))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cache::{CacheBackend, KeyError};
+	use cache::binary::Binary;
+	use std::env;
+	use std::fs::{OpenOptions, remove_file};
+	use std::io::Write;
+	use std::process;
+
+	/// Returns the path of an empty pack for a test, distinct for every test and test run
+	fn pack_path(name: &str) -> String {
+		let path = env::temp_dir().join(format!("clcache_{}_{}.pack", name, process::id())).to_str().unwrap().to_string();
+		remove_pack(&path);
+
+		path
+	}
+
+	fn remove_pack(path: &str) {
+		let _ = remove_file(path);
+		let _ = remove_file(format!("{}.compact", path));
+	}
+
+	#[test]
+	fn it_recovers_the_entries_after_reopening() {
+		let path = pack_path("reopen");
+		{
+			let mut pack = PackBackend::open(path.clone()).unwrap();
+			pack.put(&"a".to_string(), &Binary::from(vec![1, 2])).unwrap();
+			pack.put(&"b".to_string(), &Binary::from(vec![3])).unwrap();
+			pack.delete(&"b".to_string()).unwrap();
+		}
+
+		let pack = PackBackend::open_read_only(path.clone()).unwrap();
+		assert_eq!(pack.get(&"a".to_string()).unwrap(), Binary::from(vec![1, 2]));
+		assert!(pack.get(&"b".to_string()).is_err());
+
+		remove_pack(&path);
+	}
+
+	#[test]
+	fn it_frees_the_dead_entries_on_compaction() {
+		let path = pack_path("compact");
+		let mut pack = PackBackend::open(path.clone()).unwrap();
+		pack.put(&"a".to_string(), &Binary::from(vec![1, 2])).unwrap();
+		pack.put(&"a".to_string(), &Binary::from(vec![3, 4])).unwrap();
+		assert_eq!(pack.dead_bytes(), 1 + 4 + 1 + 8 + 2);
+
+		pack.compact().unwrap();
+
+		assert_eq!(pack.dead_bytes(), 0);
+		assert_eq!(pack.get(&"a".to_string()).unwrap(), Binary::from(vec![3, 4]));
+
+		remove_pack(&path);
+	}
+
+	#[test]
+	fn it_ignores_a_record_truncated_inside_its_header() {
+		let path = pack_path("truncated_header");
+		{
+			let mut pack = PackBackend::open(path.clone()).unwrap();
+			pack.put(&"a".to_string(), &Binary::from(vec![1, 2])).unwrap();
+		}
+
+		let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+		file.write_all(&[RECORD_PUT, 1, 0]).unwrap();
+
+		let mut pack = PackBackend::open(path.clone()).unwrap();
+		assert_eq!(pack.get(&"a".to_string()).unwrap(), Binary::from(vec![1, 2]));
+		pack.put(&"b".to_string(), &Binary::from(vec![3])).unwrap();
+		assert_eq!(pack.get(&"b".to_string()).unwrap(), Binary::from(vec![3]));
+
+		remove_pack(&path);
+	}
+
+	#[test]
+	fn it_counts_the_same_dead_bytes_after_reopening() {
+		let path = pack_path("dead_bytes");
+		let dead_bytes = {
+			let mut pack = PackBackend::open(path.clone()).unwrap();
+			pack.put(&"a".to_string(), &Binary::from(vec![1, 2])).unwrap();
+			pack.put(&"a".to_string(), &Binary::from(vec![3])).unwrap();
+			pack.put(&"b".to_string(), &Binary::from(vec![4])).unwrap();
+			pack.delete(&"b".to_string()).unwrap();
+
+			pack.dead_bytes()
+		};
+
+		assert_eq!(PackBackend::open_read_only(path.clone()).unwrap().dead_bytes(), dead_bytes);
+
+		remove_pack(&path);
+	}
+
+	#[cfg(unix)]
+	#[test]
+	fn it_has_a_single_writer() {
+		let path = pack_path("single_writer");
+		let _writer = PackBackend::open(path.clone()).unwrap();
+
+		assert!(PackBackend::open(path.clone()).is_err());
+		assert!(PackBackend::open_read_only(path.clone()).is_ok());
+
+		remove_pack(&path);
+	}
+
+	#[test]
+	fn it_can_not_write_on_a_read_only_pack() {
+		let path = pack_path("read_only");
+		PackBackend::open(path.clone()).unwrap();
+
+		let mut pack = PackBackend::open_read_only(path.clone()).unwrap();
+		match pack.put(&"a".to_string(), &Binary::from(vec![1])) {
+			Err(KeyError::ReadOnly) => (),
+			_ => panic!("Read-only packs should reject puts"),
+		}
+
+		remove_pack(&path);
+	}
+}
@@ -0,0 +1,379 @@
+use cache::{CacheBackend, KeyError};
+use cache::binary::Binary;
+use std::cell::RefCell;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::io::{Error as IoError, ErrorKind};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+#[derive(Debug, PartialEq)]
+enum Reply {
+	Status(String),
+	Error(String),
+	Integer(i64),
+	Bulk(Option<Vec<u8>>),
+}
+
+type Connection = BufReader<TcpStream>;
+
+/// Longest bulk string accepted on a reply, which is also the limit of the Redis server
+const MAX_BULK_LENGTH: i64 = 512 * 1024 * 1024;
+
+/// Backend that stores the binaries on a server which speaks the Redis protocol (RESP).
+///
+/// All the keys are stored with the given prefix and, if a TTL is set, they expire after
+/// that amount of seconds. The backend runs a command at a time, so it keeps a single
+/// connection, which is reused between requests and opened again when it fails.
+pub struct RedisBackend {
+	address: String,
+	prefix: String,
+	ttl: Option<u64>,
+	timeout: Duration,
+	connection: RefCell<Option<Connection>>,
+}
+
+impl RedisBackend {
+	/// Creates a backend for the server on `address` (`host:port`)
+	pub fn new(address: String, prefix: String) -> RedisBackend {
+		RedisBackend {
+			address: address,
+			prefix: prefix,
+			ttl: None,
+			timeout: Duration::from_secs(5),
+			connection: RefCell::new(None),
+		}
+	}
+
+	/// Seconds after which the stored entries will expire
+	pub fn set_ttl(&mut self, ttl: Option<u64>) {
+		self.ttl = ttl;
+	}
+
+	pub fn set_timeout(&mut self, timeout: Duration) {
+		self.timeout = timeout;
+	}
+
+	fn prefixed(&self, key: &String) -> Vec<u8> {
+		format!("{}{}", self.prefix, key).into_bytes()
+	}
+
+	fn command(&self, args: &[&[u8]]) -> Result<Reply, KeyError> {
+		let kept = self.connection.borrow_mut().take();
+		let reused = kept.is_some();
+		let mut connection = match kept {
+			Some(connection) => connection,
+			None => try!{self.connect()},
+		};
+
+		let mut result = Self::send(&mut connection, args);
+		if reused && result.is_err() {
+			// The server may have closed the idle connection: Retry once on a new one
+			debug!("Redis connection to {} failed, reconnecting", self.address);
+			connection = try!{self.connect()};
+			result = Self::send(&mut connection, args);
+		}
+
+		match result {
+			Ok(reply) => {
+				*self.connection.borrow_mut() = Some(connection);
+
+				Ok(reply)
+			},
+			Err(error) => {
+				// The connection is dropped, as it may have unread data
+				warn!("Error on redis connection to {}: {}", self.address, error);
+				Err(KeyError::IoError)
+			},
+		}
+	}
+
+	fn connect(&self) -> Result<Connection, KeyError> {
+		let mut last_error = None;
+
+		for address in try!{self.address.to_socket_addrs()} {
+			match TcpStream::connect_timeout(&address, self.timeout) {
+				Ok(stream) => {
+					try!{stream.set_read_timeout(Some(self.timeout))};
+					try!{stream.set_write_timeout(Some(self.timeout))};
+
+					return Ok(BufReader::new(stream));
+				},
+				Err(error) => last_error = Some(error),
+			}
+		}
+
+		match last_error {
+			None => warn!("Could not resolve redis address {}", self.address),
+			Some(error) => warn!("Could not connect to redis on {}: {}", self.address, error),
+		}
+
+		Err(KeyError::IoError)
+	}
+
+	fn send(connection: &mut Connection, args: &[&[u8]]) -> Result<Reply, IoError> {
+		try!{connection.get_mut().write_all(&encode_command(args))};
+
+		read_reply(connection)
+	}
+
+	fn expect_ok(reply: Reply) -> Result<(), KeyError> {
+		match reply {
+			Reply::Status(_) | Reply::Integer(_) => Ok(()),
+			other => {
+				warn!("Unexpected reply from redis: {:?}", other);
+				Err(KeyError::IoError)
+			},
+		}
+	}
+}
+
+impl CacheBackend for RedisBackend {
+	fn get(&self, key: &String) -> Result<Binary, KeyError> {
+		let key = self.prefixed(key);
+
+		match try!{self.command(&[b"GET", &key])} {
+			Reply::Bulk(Some(payload)) => Ok(Binary::from(payload)),
+			Reply::Bulk(None) => Err(KeyError::KeyNotFound),
+			other => {
+				warn!("Unexpected reply from redis: {:?}", other);
+				Err(KeyError::IoError)
+			},
+		}
+	}
+
+	fn put(&mut self, key: &String, payload: &Binary) -> Result<(), KeyError> {
+		let key = self.prefixed(key);
+
+		// The expiration is set on the same command, so an entry is never stored without it
+		let reply = match self.ttl {
+			None => try!{self.command(&[b"SET", &key, payload])},
+			Some(ttl) => {
+				let ttl = ttl.to_string();
+				try!{self.command(&[b"SET", &key, payload, b"EX", ttl.as_bytes()])}
+			},
+		};
+
+		Self::expect_ok(reply)
+	}
+
+	fn delete(&mut self, key: &String) -> Result<(), KeyError> {
+		let key = self.prefixed(key);
+
+		match try!{self.command(&[b"DEL", &key])} {
+			Reply::Integer(0) => Err(KeyError::KeyNotFound),
+			reply => Self::expect_ok(reply),
+		}
+	}
+}
+
+fn encode_command(args: &[&[u8]]) -> Vec<u8> {
+	let mut command = format!("*{}\r\n", args.len()).into_bytes();
+
+	for arg in args {
+		command.extend(format!("${}\r\n", arg.len()).into_bytes());
+		command.extend(arg.iter());
+		command.extend(b"\r\n".iter());
+	}
+
+	command
+}
+
+fn read_line<R: BufRead>(reader: &mut R) -> Result<String, IoError> {
+	let mut line = String::new();
+	try!{reader.read_line(&mut line)};
+
+	if !line.ends_with("\r\n") {
+		return Err(IoError::new(ErrorKind::UnexpectedEof, "Incomplete redis reply"));
+	}
+
+	let len = line.len() - 2;
+	line.truncate(len);
+
+	Ok(line)
+}
+
+fn read_reply<R: BufRead>(reader: &mut R) -> Result<Reply, IoError> {
+	let line = try!{read_line(reader)};
+	let invalid = || IoError::new(ErrorKind::InvalidData, "Invalid redis reply");
+
+	if line.is_empty() {
+		return Err(invalid());
+	}
+
+	let (kind, value) = line.split_at(1);
+	match kind {
+		"+" => Ok(Reply::Status(value.to_string())),
+		"-" => Ok(Reply::Error(value.to_string())),
+		":" => value.parse::<i64>().map(Reply::Integer).map_err(|_| invalid()),
+		"$" => {
+			let len = try!{value.parse::<i64>().map_err(|_| invalid())};
+			if len < 0 {
+				return Ok(Reply::Bulk(None));
+			}
+
+			// The length is checked before allocating, so a broken server can not exhaust the memory
+			if len > MAX_BULK_LENGTH {
+				return Err(IoError::new(ErrorKind::InvalidData, "Redis bulk string too long"));
+			}
+
+			// Payload + CRLF
+			let mut payload = vec![0u8; len as usize + 2];
+			try!{reader.read_exact(&mut payload)};
+			payload.truncate(len as usize);
+
+			Ok(Reply::Bulk(Some(payload)))
+		},
+		_ => Err(invalid()),
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use super::{encode_command, read_reply, Reply};
+	use cache::{CacheBackend, KeyError};
+	use cache::binary::Binary;
+	use std::collections::HashMap;
+	use std::io::{BufRead, BufReader, Read, Write};
+	use std::net::{Shutdown, TcpListener, TcpStream};
+	use std::sync::{Arc, Mutex};
+	use std::sync::atomic::{AtomicUsize, Ordering};
+	use std::thread;
+
+	struct Server {
+		address: String,
+		data: Arc<Mutex<HashMap<Vec<u8>, Vec<u8>>>>,
+		ttls: Arc<Mutex<HashMap<Vec<u8>, String>>>,
+		connections: Arc<AtomicUsize>,
+		streams: Arc<Mutex<Vec<TcpStream>>>,
+	}
+
+	// Minimal stand-in for a redis server: it only understands GET, SET (with EX) and DEL
+	fn spawn_server() -> Server {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let server = Server {
+			address: listener.local_addr().unwrap().to_string(),
+			data: Arc::new(Mutex::new(HashMap::new())),
+			ttls: Arc::new(Mutex::new(HashMap::new())),
+			connections: Arc::new(AtomicUsize::new(0)),
+			streams: Arc::new(Mutex::new(Vec::new())),
+		};
+
+		let (data, ttls, connections, streams) = (server.data.clone(), server.ttls.clone(), server.connections.clone(), server.streams.clone());
+		thread::spawn(move || {
+			for stream in listener.incoming() {
+				let stream = stream.unwrap();
+				let (data, ttls) = (data.clone(), ttls.clone());
+				connections.fetch_add(1, Ordering::SeqCst);
+				streams.lock().unwrap().push(stream.try_clone().unwrap());
+
+				thread::spawn(move || {
+					let mut reader = BufReader::new(stream);
+					while let Some(args) = read_command(&mut reader) {
+						let reply = execute(&args, &data, &ttls);
+						reader.get_mut().write_all(&reply).unwrap();
+					}
+				});
+			}
+		});
+
+		server
+	}
+
+	fn read_command(reader: &mut BufReader<TcpStream>) -> Option<Vec<Vec<u8>>> {
+		let mut line = String::new();
+		if reader.read_line(&mut line).unwrap_or(0) == 0 {
+			return None;
+		}
+
+		let amount = line.trim()[1..].parse::<usize>().unwrap();
+		let mut args = Vec::new();
+		for _ in 0..amount {
+			match read_reply(reader).unwrap() {
+				Reply::Bulk(Some(arg)) => args.push(arg),
+				_ => panic!("Arguments should be bulk strings"),
+			}
+		}
+
+		Some(args)
+	}
+
+	fn execute(args: &Vec<Vec<u8>>, data: &Mutex<HashMap<Vec<u8>, Vec<u8>>>, ttls: &Mutex<HashMap<Vec<u8>, String>>) -> Vec<u8> {
+		let mut data = data.lock().unwrap();
+
+		match &args[0][..] {
+			b"GET" => match data.get(&args[1]) {
+				None => b"$-1\r\n".to_vec(),
+				Some(payload) => {
+					let mut reply = format!("${}\r\n", payload.len()).into_bytes();
+					reply.extend(payload.iter());
+					reply.extend(b"\r\n".iter());
+					reply
+				},
+			},
+			b"SET" => {
+				data.insert(args[1].clone(), args[2].clone());
+				if args.len() == 5 && &args[3][..] == b"EX" {
+					ttls.lock().unwrap().insert(args[1].clone(), String::from_utf8(args[4].clone()).unwrap());
+				}
+				b"+OK\r\n".to_vec()
+			},
+			b"DEL" => format!(":{}\r\n", data.remove(&args[1]).map(|_| 1).unwrap_or(0)).into_bytes(),
+			_ => b"-ERR unknown command\r\n".to_vec(),
+		}
+	}
+
+	#[test]
+	fn it_encodes_commands_as_arrays_of_bulk_strings() {
+		assert_eq!(encode_command(&[b"GET", b"key"]), b"*2\r\n$3\r\nGET\r\n$3\r\nkey\r\n".to_vec());
+	}
+
+	#[test]
+	fn it_rejects_bulk_strings_longer_than_the_limit() {
+		let mut reply: &[u8] = b"$1073741824\r\n";
+
+		assert_eq!(read_reply(&mut reply).unwrap_err().kind(), ErrorKind::InvalidData);
+	}
+
+	#[test]
+	fn it_stores_prefixed_keys_with_ttl() {
+		let server = spawn_server();
+		let mut redis = RedisBackend::new(server.address.clone(), "clcache:".to_string());
+		redis.set_ttl(Some(60));
+
+		redis.put(&"key".to_string(), &Binary::from(vec![1, 2, 3])).unwrap();
+
+		assert_eq!(redis.get(&"key".to_string()).unwrap(), Binary::from(vec![1, 2, 3]));
+		assert!(server.data.lock().unwrap().contains_key(&b"clcache:key".to_vec()));
+		assert_eq!(server.ttls.lock().unwrap()[&b"clcache:key".to_vec()], "60");
+	}
+
+	#[test]
+	fn it_reuses_the_connection() {
+		let server = spawn_server();
+		let mut redis = RedisBackend::new(server.address.clone(), "".to_string());
+
+		redis.put(&"key".to_string(), &Binary::from(vec![1])).unwrap();
+		redis.delete(&"key".to_string()).unwrap();
+		match redis.get(&"key".to_string()) {
+			Err(KeyError::KeyNotFound) => (),
+			_ => panic!("Deleted keys should not be found"),
+		}
+
+		assert_eq!(server.connections.load(Ordering::SeqCst), 1);
+	}
+
+	#[test]
+	fn it_reconnects_when_the_connection_was_closed() {
+		let server = spawn_server();
+		let mut redis = RedisBackend::new(server.address.clone(), "".to_string());
+		redis.put(&"key".to_string(), &Binary::from(vec![1])).unwrap();
+
+		for stream in server.streams.lock().unwrap().iter() {
+			stream.shutdown(Shutdown::Both).unwrap();
+		}
+
+		assert_eq!(redis.get(&"key".to_string()).unwrap(), Binary::from(vec![1]));
+		assert_eq!(server.connections.load(Ordering::SeqCst), 2);
+	}
+}
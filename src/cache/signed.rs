@@ -0,0 +1,139 @@
+use cache::binary::Binary;
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::sha2::Sha256;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+/// Signed entries start with this magic, followed by the version of the format and the signature
+const MAGIC: &'static [u8] = b"CLCS";
+const VERSION: u8 = 1;
+const SIGNATURE_LENGTH: usize = 32;
+const HEADER_LENGTH: usize = 4 + 1 + SIGNATURE_LENGTH;
+
+#[derive(Debug, PartialEq)]
+pub enum SignatureError {
+	/// The entry was stored without signature
+	Unsigned,
+	UnsupportedVersion(u8),
+	/// The entry was modified, stored with another secret or moved from another key
+	Invalid,
+}
+
+/// Signs the entries with HMAC-SHA256, so the binaries stored on a shared storage can not be
+/// replaced by someone that does not know the secret. The signature covers the key of the entry,
+/// so a valid entry can not be copied to another key either
+pub struct Signer {
+	secret: Vec<u8>,
+}
+
+impl Signer {
+	pub fn new(secret: &[u8]) -> Signer {
+		Signer {
+			secret: secret.to_vec(),
+		}
+	}
+
+	/// Returns the entry to store on `key`: the signature followed by the binary
+	pub fn sign(&self, key: &str, binary: &Binary) -> Binary {
+		let mut entry = Vec::with_capacity(HEADER_LENGTH + binary.len());
+		entry.extend_from_slice(MAGIC);
+		entry.push(VERSION);
+		entry.extend_from_slice(self.signature(key, &binary).code());
+		entry.extend_from_slice(&binary);
+
+		Binary::from(entry)
+	}
+
+	/// Returns the binary of an entry read from `key` if its signature is valid. The binary is not copied
+	pub fn verify(&self, key: &str, entry: &Binary) -> Result<Binary, SignatureError> {
+		if entry.len() < MAGIC.len() + 1 || &entry[..MAGIC.len()] != MAGIC {
+			return Err(SignatureError::Unsigned);
+		}
+
+		let version = entry[MAGIC.len()];
+		if version != VERSION {
+			return Err(SignatureError::UnsupportedVersion(version));
+		}
+
+		if entry.len() < HEADER_LENGTH {
+			return Err(SignatureError::Invalid);
+		}
+
+		let binary = entry.skip(HEADER_LENGTH);
+		let stored = MacResult::new(&entry[MAGIC.len() + 1..HEADER_LENGTH]);
+
+		// MacResult compares in constant time
+		if self.signature(key, &binary) == stored {
+			Ok(binary)
+		} else {
+			Err(SignatureError::Invalid)
+		}
+	}
+
+	/// Returns the contents of a signed entry without checking its signature, or the entry
+	/// itself if it's not signed. It's only meant to inspect the entries
+	pub fn strip(entry: &Binary) -> Binary {
+		let signed = entry.len() >= HEADER_LENGTH && &entry[..MAGIC.len()] == MAGIC && entry[MAGIC.len()] == VERSION;
+
+		if signed {
+			entry.skip(HEADER_LENGTH)
+		} else {
+			entry.clone()
+		}
+	}
+
+	fn signature(&self, key: &str, binary: &[u8]) -> MacResult {
+		let mut hmac = Hmac::new(Sha256::new(), &self.secret);
+		hmac.input(&[VERSION]);
+		hmac.input(key.as_bytes());
+		// The separator avoids ambiguities between the end of the key and the binary
+		hmac.input(&[0]);
+		hmac.input(binary);
+
+		hmac.result()
+	}
+}
+
+impl Display for SignatureError {
+	fn fmt(&self, f: &mut Formatter) -> FmtResult {
+		match *self {
+			SignatureError::Unsigned => write!(f, "Entry without signature"),
+			SignatureError::UnsupportedVersion(version) => write!(f, "Unsupported signature version: {}", version),
+			SignatureError::Invalid => write!(f, "Invalid signature"),
+		}
+	}
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cache::binary::Binary;
+
+	#[test]
+	fn it_verifies_its_own_signatures() {
+		let signer = Signer::new(b"secret");
+		let binary = Binary::from(vec![1u8, 2, 3]);
+
+		assert_eq!(signer.verify("key", &signer.sign("key", &binary)), Ok(binary));
+	}
+
+	#[test]
+	fn it_rejects_modified_and_moved_entries() {
+		let signer = Signer::new(b"secret");
+		let entry = signer.sign("key", &Binary::from(vec![1u8, 2, 3]));
+		let mut modified = entry.to_vec();
+		*modified.last_mut().unwrap() = 4;
+
+		assert_eq!(signer.verify("key", &Binary::from(modified)), Err(SignatureError::Invalid));
+		assert_eq!(signer.verify("other", &entry), Err(SignatureError::Invalid));
+		assert_eq!(Signer::new(b"other").verify("key", &entry), Err(SignatureError::Invalid));
+	}
+
+	#[test]
+	fn it_rejects_unsigned_entries() {
+		let signer = Signer::new(b"secret");
+
+		assert_eq!(signer.verify("key", &Binary::from(vec![1u8, 2, 3])), Err(SignatureError::Unsigned));
+		assert_eq!(signer.verify("key", &Binary::from(b"CLCS\x02".to_vec())), Err(SignatureError::UnsupportedVersion(2)));
+	}
+}
@@ -0,0 +1,354 @@
+use cache::{CacheBackend, KeyError};
+use cache::binary::Binary;
+use rusqlite::{Connection, ErrorCode, OpenFlags, Error as SqliteError};
+use rusqlite::types::ToSql;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+const SCHEMA: &'static str = "
+	CREATE TABLE IF NOT EXISTS entries (
+		key TEXT PRIMARY KEY NOT NULL,
+		payload BLOB NOT NULL,
+		size INTEGER NOT NULL,
+		created_at INTEGER NOT NULL,
+		last_access INTEGER NOT NULL,
+		hits INTEGER NOT NULL DEFAULT 0
+	);
+	CREATE INDEX IF NOT EXISTS entries_last_access ON entries (last_access);
+";
+
+/// Amount of accessed entries kept on memory before their access times are written
+const MAX_PENDING_ACCESSES: usize = 64;
+
+/// Information stored next to each binary
+#[derive(Clone, Debug, PartialEq)]
+pub struct EntryInfo {
+	pub key: String,
+	pub size: u64,
+	pub created_at: u64,
+	pub last_access: u64,
+	pub hits: u64,
+}
+
+/// Backend that stores all the entries on a single SQLite database.
+///
+/// The database uses WAL journaling, so several processes can read it while another
+/// one is writing, and the binaries of all the devices of a program are stored on
+/// a single transaction. The hits do not write on the database: their access times are
+/// kept on memory and written on batches, so the reads do not take the write lock.
+pub struct SqliteBackend {
+	connection: Connection,
+	read_only: bool,
+	max_size: Option<u64>,
+	// Last access and hits of the entries read since the last write of the access times
+	accesses: RefCell<HashMap<String, (i64, i64)>>,
+}
+
+impl From<SqliteError> for KeyError {
+	fn from(error: SqliteError) -> Self {
+		match error {
+			SqliteError::QueryReturnedNoRows => KeyError::KeyNotFound,
+			_ => {
+				warn!("SQLite error: {}", error);
+				KeyError::IoError
+			},
+		}
+	}
+}
+
+impl SqliteBackend {
+	/// Opens the database on the given path, creating it if needed
+	pub fn open(path: &str) -> Result<SqliteBackend, KeyError> {
+		let connection = try!{Connection::open(path)};
+		try!{connection.busy_timeout(Duration::from_secs(5))};
+		// The journal mode query returns a row, so it can not be run with `execute`
+		let _: String = try!{connection.query_row("PRAGMA journal_mode = WAL", &[], |row| row.get(0))};
+		try!{connection.execute_batch(SCHEMA)};
+
+		Ok(SqliteBackend {
+			connection: connection,
+			read_only: false,
+			max_size: None,
+			accesses: RefCell::new(HashMap::new()),
+		})
+	}
+
+	/// Opens an existing database without ever writing on it
+	pub fn open_read_only(path: &str) -> Result<SqliteBackend, KeyError> {
+		let connection = try!{Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY)};
+		try!{connection.busy_timeout(Duration::from_secs(5))};
+
+		Ok(SqliteBackend {
+			connection: connection,
+			read_only: true,
+			max_size: None,
+			accesses: RefCell::new(HashMap::new()),
+		})
+	}
+
+	/// Once set, the least recently used entries are removed after each store to keep
+	/// the database under `max_size` bytes
+	pub fn set_max_size(&mut self, max_size: Option<u64>) {
+		self.max_size = max_size;
+	}
+
+	/// Returns the information of all the entries, from the most recently used to the least one
+	pub fn entries(&self) -> Result<Vec<EntryInfo>, KeyError> {
+		self.flush_accesses();
+
+		let mut statement = try!{self.connection.prepare(
+			"SELECT key, size, created_at, last_access, hits FROM entries ORDER BY last_access DESC, rowid DESC"
+		)};
+
+		let rows = try!{statement.query_map(&[], |row| {
+			EntryInfo {
+				key: row.get(0),
+				size: row.get::<_, i64>(1) as u64,
+				created_at: row.get::<_, i64>(2) as u64,
+				last_access: row.get::<_, i64>(3) as u64,
+				hits: row.get::<_, i64>(4) as u64,
+			}
+		})};
+
+		let mut entries = Vec::new();
+		for row in rows {
+			entries.push(try!{row});
+		}
+
+		Ok(entries)
+	}
+
+	pub fn total_size(&self) -> Result<u64, KeyError> {
+		let size: i64 = try!{self.connection.query_row("SELECT COALESCE(SUM(size), 0) FROM entries", &[], |row| row.get(0))};
+
+		Ok(size as u64)
+	}
+
+	/// Removes the entries that were not accessed on the last `seconds`. Returns the amount of removed entries
+	pub fn remove_unused_since(&mut self, seconds: u64) -> Result<usize, KeyError> {
+		if self.read_only {
+			return Err(KeyError::ReadOnly);
+		}
+
+		self.flush_accesses();
+
+		let limit = now().saturating_sub(seconds) as i64;
+		let removed = try!{self.connection.execute("DELETE FROM entries WHERE last_access < ?1", &[&limit as &ToSql])};
+
+		Ok(removed)
+	}
+
+	/// Removes the least recently used entries until the total size is below `max_size`
+	pub fn shrink_to(&mut self, max_size: u64) -> Result<usize, KeyError> {
+		if self.read_only {
+			return Err(KeyError::ReadOnly);
+		}
+
+		let mut total = try!{self.total_size()};
+		let mut to_remove = Vec::new();
+
+		for entry in try!{self.entries()}.into_iter().rev() {
+			if total <= max_size {
+				break;
+			}
+
+			total -= entry.size;
+			to_remove.push(entry.key);
+		}
+
+		let transaction = try!{self.connection.transaction()};
+		for key in to_remove.iter() {
+			try!{transaction.execute("DELETE FROM entries WHERE key = ?1", &[key as &ToSql])};
+		}
+		try!{transaction.commit()};
+
+		Ok(to_remove.len())
+	}
+
+	/// Writes the access times of the entries read since the last call. A busy database keeps them
+	/// on memory for the next call, as the reads can not fail because of them
+	pub fn flush_accesses(&self) {
+		if self.read_only || self.accesses.borrow().is_empty() {
+			return;
+		}
+
+		match self.write_accesses() {
+			Ok(()) => self.accesses.borrow_mut().clear(),
+			Err(SqliteError::SqliteFailure(ref error, _)) if error.code == ErrorCode::DatabaseBusy => {
+				debug!("The database is busy: The access times will be written later");
+			},
+			Err(error) => {
+				debug!("Could not update the access times: {}", error);
+				self.accesses.borrow_mut().clear();
+			},
+		}
+	}
+
+	fn write_accesses(&self) -> Result<(), SqliteError> {
+		try!{self.connection.execute_batch("BEGIN")};
+
+		for (key, &(last_access, hits)) in self.accesses.borrow().iter() {
+			let update = self.connection.execute(
+				"UPDATE entries SET last_access = MAX(last_access, ?1), hits = hits + ?2 WHERE key = ?3",
+				&[&last_access as &ToSql, &hits, key]
+			);
+
+			if let Err(error) = update {
+				let _ = self.connection.execute_batch("ROLLBACK");
+				return Err(error);
+			}
+		}
+
+		self.connection.execute_batch("COMMIT")
+	}
+}
+
+impl Drop for SqliteBackend {
+	fn drop(&mut self) {
+		self.flush_accesses();
+	}
+}
+
+impl CacheBackend for SqliteBackend {
+	fn get(&self, key: &String) -> Result<Binary, KeyError> {
+		let payload: Vec<u8> = try!{self.connection.query_row(
+			"SELECT payload FROM entries WHERE key = ?1",
+			&[key as &ToSql],
+			|row| row.get(0)
+		)};
+
+		if !self.read_only {
+			let pending = {
+				let mut accesses = self.accesses.borrow_mut();
+				let access = accesses.entry(key.clone()).or_insert((0, 0));
+				*access = (now() as i64, access.1 + 1);
+
+				accesses.len()
+			};
+
+			if pending >= MAX_PENDING_ACCESSES {
+				self.flush_accesses();
+			}
+		}
+
+		Ok(Binary::from(payload))
+	}
+
+	fn put(&mut self, key: &String, payload: &Binary) -> Result<(), KeyError> {
+		self.put_all(&[(key.clone(), payload.clone())])
+	}
+
+	fn put_all(&mut self, entries: &[(String, Binary)]) -> Result<(), KeyError> {
+		if self.read_only {
+			return Err(KeyError::ReadOnly);
+		}
+
+		let created = now() as i64;
+		let transaction = try!{self.connection.transaction()};
+
+		for &(ref key, ref payload) in entries {
+			let size = payload.len() as i64;
+			try!{transaction.execute(
+				"INSERT OR REPLACE INTO entries (key, payload, size, created_at, last_access, hits) VALUES (?1, ?2, ?3, ?4, ?4, 0)",
+				&[key as &ToSql, &payload.as_slice(), &size, &created]
+			)};
+		}
+
+		try!{transaction.commit()};
+
+		if let Some(max_size) = self.max_size {
+			try!{self.shrink_to(max_size)};
+		}
+
+		Ok(())
+	}
+
+	fn delete(&mut self, key: &String) -> Result<(), KeyError> {
+		if self.read_only {
+			return Err(KeyError::ReadOnly);
+		}
+
+		match try!{self.connection.execute("DELETE FROM entries WHERE key = ?1", &[key as &ToSql])} {
+			0 => Err(KeyError::KeyNotFound),
+			_ => Ok(()),
+		}
+	}
+
+	fn is_read_only(&self) -> bool {
+		self.read_only
+	}
+
+	fn keys(&self) -> Result<Vec<String>, KeyError> {
+		Ok(try!{self.entries()}.into_iter().map(|entry| entry.key).collect())
+	}
+}
+
+fn now() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_secs()).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cache::{CacheBackend, KeyError};
+	use cache::binary::Binary;
+
+	#[test]
+	fn it_stores_all_the_entries_on_put_all() {
+		let mut db = SqliteBackend::open(":memory:").unwrap();
+		db.put_all(&[
+			("a".to_string(), Binary::from(vec![1, 2])),
+			("b".to_string(), Binary::from(vec![3])),
+		]).unwrap();
+
+		assert_eq!(db.get(&"a".to_string()).unwrap(), Binary::from(vec![1, 2]));
+		assert_eq!(db.total_size().unwrap(), 3);
+		assert_eq!(db.entries().unwrap().len(), 2);
+	}
+
+	#[test]
+	fn it_removes_entries_until_the_size_fits() {
+		let mut db = SqliteBackend::open(":memory:").unwrap();
+		db.put(&"a".to_string(), &Binary::from(vec![1, 2])).unwrap();
+		db.put(&"b".to_string(), &Binary::from(vec![3, 4])).unwrap();
+
+		assert_eq!(db.shrink_to(2).unwrap(), 1);
+		assert_eq!(db.total_size().unwrap(), 2);
+	}
+
+	#[test]
+	fn it_keeps_the_size_under_the_limit_after_storing() {
+		let mut db = SqliteBackend::open(":memory:").unwrap();
+		db.set_max_size(Some(3));
+
+		db.put(&"a".to_string(), &Binary::from(vec![1, 2])).unwrap();
+		db.put(&"b".to_string(), &Binary::from(vec![3, 4])).unwrap();
+
+		assert_eq!(db.total_size().unwrap(), 2);
+		assert!(db.get(&"b".to_string()).is_ok());
+	}
+
+	#[test]
+	fn it_writes_the_access_times_on_batches() {
+		let mut db = SqliteBackend::open(":memory:").unwrap();
+		db.put(&"a".to_string(), &Binary::from(vec![1])).unwrap();
+		db.get(&"a".to_string()).unwrap();
+		db.get(&"a".to_string()).unwrap();
+
+		let stored_hits: i64 = db.connection.query_row("SELECT hits FROM entries", &[], |row| row.get(0)).unwrap();
+		assert_eq!(stored_hits, 0);
+		assert_eq!(db.entries().unwrap()[0].hits, 2);
+	}
+
+	#[test]
+	fn it_returns_key_not_found_on_missing_entries() {
+		let mut db = SqliteBackend::open(":memory:").unwrap();
+
+		match db.delete(&"missing".to_string()) {
+			Err(KeyError::KeyNotFound) => (),
+			_ => panic!("Missing keys should not be deleted"),
+		}
+		assert!(db.get(&"missing".to_string()).is_err());
+	}
+}
@@ -1,8 +1,12 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
+use std::cell::RefCell;
+#[cfg(test)]
+use std::rc::Rc;
 use cache::{CacheBackend, KeyError};
+use cache::binary::Binary;
 
 pub struct Volatile {
-	map: BTreeMap<String, Vec<u8>>,
+	map: BTreeMap<String, Binary>,
 }
 
 impl Volatile {
@@ -14,7 +18,7 @@ impl Volatile {
 }
 
 impl CacheBackend for Volatile {
-    fn get(&self, key: &String) -> Result<Vec<u8>, KeyError> {
+    fn get(&self, key: &String) -> Result<Binary, KeyError> {
         let content = self.map.get(key);
 
         match content {
@@ -23,21 +27,258 @@ impl CacheBackend for Volatile {
         }
     }
 
-    fn put(&mut self, key: &String, payload: &Vec<u8>) -> Result<(), KeyError> {
+    fn put(&mut self, key: &String, payload: &Binary) -> Result<(), KeyError> {
     	self.map.insert(key.clone(), payload.clone());
 
     	Ok(())
     }
+
+    fn delete(&mut self, key: &String) -> Result<(), KeyError> {
+    	match self.map.remove(key) {
+    		None => Err(KeyError::KeyNotFound),
+    		Some(_) => Ok(()),
+    	}
+    }
+
+    fn keys(&self) -> Result<Vec<String>, KeyError> {
+    	Ok(self.map.keys().cloned().collect())
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct VolatileStats {
+	pub hits: u64,
+	pub misses: u64,
+	pub insertions: u64,
+	pub evictions: u64,
+	/// Binaries not stored because they were bigger than the cache
+	pub rejections: u64,
+	pub entries: usize,
+	pub size: usize,
+}
+
+struct LruEntry {
+	payload: Binary,
+	tick: u64,
+}
+
+struct LruState {
+	entries: HashMap<String, LruEntry>,
+	order: BTreeMap<u64, String>,
+	tick: u64,
+	stats: VolatileStats,
+}
+
+impl LruState {
+	fn touch(&mut self, key: &String) {
+		self.tick += 1;
+		let tick = self.tick;
+
+		if let Some(entry) = self.entries.get_mut(key) {
+			self.order.remove(&entry.tick);
+			entry.tick = tick;
+			self.order.insert(tick, key.clone());
+		}
+	}
+
+	fn remove(&mut self, key: &String) -> bool {
+		match self.entries.remove(key) {
+			None => false,
+			Some(entry) => {
+				self.order.remove(&entry.tick);
+				self.stats.entries -= 1;
+				self.stats.size -= entry.payload.len();
+
+				true
+			},
+		}
+	}
+
+	fn evict_lru(&mut self) -> bool {
+		let oldest = self.order.values().next().cloned();
+
+		match oldest {
+			None => false,
+			Some(key) => {
+				debug!("Evicting key {} from bounded volatile cache", key);
+				self.remove(&key);
+				self.stats.evictions += 1;
+
+				true
+			},
+		}
+	}
+}
+
+/// In-memory backend with a cap on the total size of the stored binaries.
+/// When the cap is reached, the least recently used entries are evicted.
+pub struct BoundedVolatile {
+	max_size: usize,
+	state: RefCell<LruState>,
+}
+
+impl BoundedVolatile {
+	pub fn new(max_size: usize) -> BoundedVolatile {
+		BoundedVolatile {
+			max_size: max_size,
+			state: RefCell::new(LruState {
+				entries: HashMap::new(),
+				order: BTreeMap::new(),
+				tick: 0,
+				stats: VolatileStats::default(),
+			}),
+		}
+	}
+
+	pub fn stats(&self) -> VolatileStats {
+		self.state.borrow().stats
+	}
+
+	pub fn max_size(&self) -> usize {
+		self.max_size
+	}
+
+	/// Stores the binary, unless it's bigger than the cache. In that case, it fails with
+	/// `KeyError::Unsupported` and the previous binary of the key is kept
+	fn insert(&mut self, key: &String, payload: Binary) -> Result<(), KeyError> {
+		let mut state = self.state.borrow_mut();
+
+		if payload.len() > self.max_size {
+			info!("Binary for key {} is bigger than the cache: Skipping it", key);
+			state.stats.rejections += 1;
+			return Err(KeyError::Unsupported);
+		}
+
+		state.remove(key);
+
+		while state.stats.size + payload.len() > self.max_size && state.evict_lru() {}
+
+		state.tick += 1;
+		let tick = state.tick;
+		state.stats.size += payload.len();
+		state.stats.entries += 1;
+		state.stats.insertions += 1;
+		state.order.insert(tick, key.clone());
+		state.entries.insert(key.clone(), LruEntry {
+			payload: payload,
+			tick: tick,
+		});
+
+		Ok(())
+	}
+}
+
+impl CacheBackend for BoundedVolatile {
+	fn get(&self, key: &String) -> Result<Binary, KeyError> {
+		let mut state = self.state.borrow_mut();
+		let payload = state.entries.get(key).map(|entry| entry.payload.clone());
+
+		match payload {
+			None => {
+				state.stats.misses += 1;
+				Err(KeyError::KeyNotFound)
+			},
+			Some(binary) => {
+				state.stats.hits += 1;
+				state.touch(key);
+				Ok(binary)
+			},
+		}
+	}
+
+	fn put(&mut self, key: &String, payload: &Binary) -> Result<(), KeyError> {
+		self.insert(key, payload.clone())
+	}
+
+	fn delete(&mut self, key: &String) -> Result<(), KeyError> {
+		if self.state.borrow_mut().remove(key) {
+			Ok(())
+		} else {
+			Err(KeyError::KeyNotFound)
+		}
+	}
+
+	fn keys(&self) -> Result<Vec<String>, KeyError> {
+		let mut keys: Vec<String> = self.state.borrow().entries.keys().cloned().collect();
+		keys.sort();
+
+		Ok(keys)
+	}
+}
+
+/// Backend that stores the entries on a `Volatile` that is shared with the test, so it can
+/// check what reached the storage through the backends built on top of it
+#[cfg(test)]
+pub struct SharedBackend(pub Rc<RefCell<Volatile>>);
+
+#[cfg(test)]
+impl CacheBackend for SharedBackend {
+	fn get(&self, key: &String) -> Result<Binary, KeyError> {
+		self.0.borrow().get(key)
+	}
+
+	fn put(&mut self, key: &String, payload: &Binary) -> Result<(), KeyError> {
+		self.0.borrow_mut().put(key, payload)
+	}
+
+	fn delete(&mut self, key: &String) -> Result<(), KeyError> {
+		self.0.borrow_mut().delete(key)
+	}
+
+	fn keys(&self) -> Result<Vec<String>, KeyError> {
+		self.0.borrow().keys()
+	}
 }
 
 #[cfg(test)]
 mod test {
 	use super::*;
 	use cache::CacheBackend;
+	use cache::binary::Binary;
 
 	#[test]
 	fn it_returns_none_on_empty_cache() {
 		let c = Volatile::new();
 		assert!(c.get(&("test".to_string())).is_err());
 	}
-}
\ No newline at end of file
+
+	#[test]
+	fn it_evicts_the_least_recently_used_entry() {
+		let mut c = BoundedVolatile::new(4);
+		c.put(&"a".to_string(), &Binary::from(vec![1, 1])).unwrap();
+		c.put(&"b".to_string(), &Binary::from(vec![2, 2])).unwrap();
+		assert!(c.get(&"a".to_string()).is_ok());
+
+		c.put(&"c".to_string(), &Binary::from(vec![3, 3])).unwrap();
+
+		assert!(c.get(&"a".to_string()).is_ok());
+		assert!(c.get(&"b".to_string()).is_err());
+		assert_eq!(c.stats().evictions, 1);
+		assert_eq!(c.stats().size, 4);
+	}
+
+	#[test]
+	fn it_shares_the_payload_between_hits() {
+		let mut c = BoundedVolatile::new(16);
+		c.put(&"a".to_string(), &Binary::from(vec![1, 2, 3])).unwrap();
+
+		let first = c.get(&"a".to_string()).unwrap();
+		let second = c.get(&"a".to_string()).unwrap();
+
+		assert_eq!(first.as_ptr(), second.as_ptr());
+		assert_eq!(c.stats().hits, 2);
+	}
+
+	#[test]
+	fn it_rejects_the_binaries_bigger_than_the_cache() {
+		let mut c = BoundedVolatile::new(4);
+		c.put(&"a".to_string(), &Binary::from(vec![1, 1])).unwrap();
+
+		match c.put(&"a".to_string(), &Binary::from(vec![2; 5])) {
+			Err(KeyError::Unsupported) => (),
+			other => panic!("Expected a rejection, found {:?}", other),
+		}
+		assert_eq!(c.get(&"a".to_string()).unwrap(), Binary::from(vec![1, 1]));
+		assert_eq!(c.stats().rejections, 1);
+	}
+}
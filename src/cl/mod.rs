@@ -31,6 +31,11 @@ impl OpenClError {
 			error_code: None,
 		}
 	}
+
+	/// OpenCL status code of the error, if it came from an OpenCL call
+	pub fn error_code(&self) -> Option<i32> {
+		self.error_code
+	}
 }
 
 impl Display for OpenClError {
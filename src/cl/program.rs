@@ -2,8 +2,8 @@ use opencl::cl::*;
 use opencl::cl::ll::*;
 use cl::context::Context;
 use cl::device::Device;
-use std::ffi::CString;
 use opencl::cl::CLStatus::*;
+use std::ffi::CString;
 use std::ptr;
 use std::mem;
 use libc;
@@ -39,15 +39,28 @@ impl Program {
     }
 
     pub fn from_source(ctx: &Context, src: &str) -> Result<Program, OpenClError> {
-    	let src = try!{CString::new(src)};
+    	Program::from_sources(ctx, &[src])
+    }
+
+    /// Creates the program from several source fragments. The lengths are sent to OpenCL,
+    /// so the fragments do not need to be NUL terminated
+    pub fn from_sources(ctx: &Context, sources: &[&str]) -> Result<Program, OpenClError> {
+    	// OpenCL reads fragments with a length of 0 as NUL terminated strings, so the empty ones are skipped
+    	let sources: Vec<&str> = sources.iter().cloned().filter(|x| x.len() > 0).collect();
+    	if sources.len() == 0 {
+    		return Err(OpenClError::from_string("Can't create program without sources".to_string()));
+    	}
+
 		let mut status: cl_int = 0;
+		let source_ptrs: Vec<*const libc::c_char> = sources.iter().map(|x| x.as_ptr() as *const libc::c_char).collect();
+		let source_lengths: Vec<libc::size_t> = sources.iter().map(|x| x.len() as libc::size_t).collect();
 
 		unsafe {
 			let program = clCreateProgramWithSource(
 				ctx.get_id(),
-				1,
-				&src.as_ptr(),
-				ptr::null(),
+				sources.len() as cl_uint,
+				source_ptrs.as_ptr(),
+				source_lengths.as_ptr(),
 				(&mut status)
 			);
 
@@ -59,19 +72,20 @@ impl Program {
 		}
     }
 
-    pub fn from_binary(ctx: &Context, devices: &Vec<Rc<Device>>, binaries: &Vec<Vec<u8>>) -> Result<Program, OpenClError> {
+    pub fn from_binary<B>(ctx: &Context, devices: &Vec<Rc<Device>>, binaries: &[B]) -> Result<Program, OpenClError> where B: AsRef<[u8]> {
     	if devices.len() == 0 {
     		return Err(OpenClError::from_string("Can't create program without devices".to_string()));
     	}
 
-    	if binaries.iter().any(|x| x.len() == 0) {
+    	if binaries.iter().any(|x| x.as_ref().len() == 0) {
     		return Err(OpenClError::from_string("Binaries need to have some size".to_string()));
     	}
 
     	unsafe {
+    		// The binaries are borrowed: they are not copied before reaching the OpenCL driver
     		let devices_ids: Vec<cl_device_id> = devices.iter().map(|x| x.get_id()).collect();
-    		let binary_sizes: Vec<u64> = binaries.iter().map(|x| x.len() as u64).collect();
-    		let binary_ptrs: Vec<*const u8> = binaries.iter().map(|x| x.as_ptr() as *const u8).collect();
+    		let binary_sizes: Vec<u64> = binaries.iter().map(|x| x.as_ref().len() as u64).collect();
+    		let binary_ptrs: Vec<*const u8> = binaries.iter().map(|x| x.as_ref().as_ptr() as *const u8).collect();
 
     		let mut errcode: cl_int = 0;
     		let program = clCreateProgramWithBinary(
@@ -101,10 +115,13 @@ impl Program {
     }
 
     pub fn build_with_options(&self, devices: &Vec<Rc<Device>>, options: &str) -> Result<(), OpenClError> {
+    	// The options have to be NUL terminated
+    	let options = try!{CString::new(options)};
+
     	unsafe
 		{
-			let option_ptr = if options.len() > 0 {
-				options.as_ptr() as *const i8
+			let option_ptr = if options.as_bytes().len() > 0 {
+				options.as_ptr()
 			} else {
 				ptr::null()
 			};
@@ -208,14 +225,14 @@ impl Program {
     		return Err(OpenClError::from_string("This program has not source".to_string()))
     	}
 
-    	let mut source = String::with_capacity(ss as usize);
+    	let mut source: Vec<u8> = repeat(0u8).take(ss as usize).collect();
 
     	unsafe {
 			let errcode = clGetProgramInfo(
     			self.prg,
     			CL_PROGRAM_SOURCE,
     			ss,
-    			source.as_mut_vec().as_mut_ptr() as *mut libc::c_void,
+    			source.as_mut_ptr() as *mut libc::c_void,
     			ptr::null_mut()
     		);
 
@@ -224,7 +241,51 @@ impl Program {
 			}
     	}
 
-    	Ok(source)
+    	// Without the trailing null char
+    	source.pop();
+
+    	String::from_utf8(source).map_err(|_| OpenClError::from_string("Program source is not valid UTF-8".to_string()))
+    }
+
+    /// Returns the context the program was created on
+    pub fn get_context(&self) -> Result<Context, OpenClError> {
+    	let mut context: cl_context = ptr::null_mut();
+
+    	unsafe {
+    		let errcode = clGetProgramInfo(
+    			self.prg,
+    			CL_PROGRAM_CONTEXT,
+    			mem::size_of::<cl_context>() as libc::size_t,
+    			(&mut context as *mut cl_context) as *mut libc::c_void,
+    			ptr::null_mut()
+    		);
+
+    		if errcode != CL_SUCCESS as cl_int {
+    			return Err(OpenClError::new("Could not get program context".to_string(), errcode));
+    		}
+    	}
+
+    	Ok(Context::from_id(context))
+    }
+
+    pub fn get_reference_count(&self) -> Result<cl_uint, OpenClError> {
+    	let mut references: cl_uint = 0;
+
+    	unsafe {
+    		let errcode = clGetProgramInfo(
+    			self.prg,
+    			CL_PROGRAM_REFERENCE_COUNT,
+    			mem::size_of::<cl_uint>() as libc::size_t,
+    			(&mut references as *mut cl_uint) as *mut libc::c_void,
+    			ptr::null_mut()
+    		);
+
+    		if errcode != CL_SUCCESS as cl_int {
+    			return Err(OpenClError::new("Could not get program reference count".to_string(), errcode));
+    		}
+    	}
+
+    	Ok(references)
     }
 
     pub fn get_devices(&self) -> Result<Vec<Rc<Device>>, OpenClError> {
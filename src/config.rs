@@ -0,0 +1,386 @@
+use cache::{Cache, CacheBackend, CacheError};
+use cache::disk::{FileSystemCache, Layout};
+use cache::pack::PackBackend;
+use cache::sqlite::SqliteBackend;
+use cache::volatile::{BoundedVolatile, Volatile};
+use error::ErrorCode;
+use env_logger::LogBuilder;
+use libc;
+use std::env;
+use std::ffi::CStr;
+use std::io::{Error, ErrorKind};
+use std::sync::{Once, ONCE_INIT};
+
+/// Version of the C API. It's increased each time a function or a struct changes
+pub const API_VERSION: u32 = 8;
+
+/// Version of `CacheConfig` expected by `cl_cache_create`
+pub const CONFIG_VERSION: u32 = 1;
+
+pub const BACKEND_FILESYSTEM: u32 = 0;
+pub const BACKEND_MEMORY: u32 = 1;
+pub const BACKEND_PACK: u32 = 2;
+pub const BACKEND_SQLITE: u32 = 3;
+
+/// Configuration of a cache created through the C API (`cl_cache_config` on the header)
+#[repr(C)]
+pub struct CacheConfig {
+	/// Must be `CONFIG_VERSION`
+	pub version: u32,
+	/// One of the `BACKEND_*` constants
+	pub backend: u32,
+	/// Directory or file of the storage. Ignored by the memory backend. File system
+	/// caches use `CLCACHE_DIR` or the XDG cache directory when it's NULL
+	pub path: *const libc::c_char,
+	/// Non zero to only serve hits from an existing storage
+	pub read_only: i32,
+	/// Maximum size in bytes of the stored binaries, or 0 for no limit.
+	/// Only supported by the memory and SQLite backends
+	pub max_size: u64,
+	/// Non zero to use the sharded layout on file system caches
+	pub sharded: i32,
+}
+
+/// Settings read from the environment, so the cache can be tuned or disabled on a node
+/// without rebuilding the applications:
+///
+/// * `CLCACHE_DIR`: Directory of the cache. By default, `$XDG_CACHE_HOME/clcache` or `~/.cache/clcache`
+/// * `CLCACHE_DISABLE`: Build all the programs from source
+/// * `CLCACHE_READONLY`: Serve hits, but do not store new binaries
+/// * `CLCACHE_MAX_SIZE`: Maximum size of the binaries, in bytes or with a `K`, `M` or `G` suffix
+/// * `CLCACHE_EXTRA_OPTIONS`: Options appended to every build
+/// * `CLCACHE_LOG`: Log filter, with the same syntax as `RUST_LOG` (for example, `info`)
+/// * `CLCACHE_SIGNING_KEY`: Secret used to sign the entries (see `Cache::set_signing_key`)
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EnvConfig {
+	pub directory: Option<String>,
+	pub disabled: bool,
+	pub read_only: bool,
+	pub max_size: Option<u64>,
+	pub extra_options: String,
+	pub log: Option<String>,
+	pub signing_key: Option<String>,
+}
+
+impl EnvConfig {
+	pub fn from_env() -> EnvConfig {
+		Self::from_vars(|name| env::var(name).ok().and_then(|x| if x.is_empty() { None } else { Some(x) }))
+	}
+
+	/// Builds the configuration with the given variable lookup, which returns `None` for unset variables
+	pub fn from_vars<F>(var: F) -> EnvConfig where F: Fn(&str) -> Option<String> {
+		let max_size = var("CLCACHE_MAX_SIZE").and_then(|value| {
+			let size = parse_size(&value);
+			if size.is_none() {
+				warn!("Ignoring invalid CLCACHE_MAX_SIZE: {}", value);
+			}
+
+			size
+		});
+
+		EnvConfig {
+			directory: var("CLCACHE_DIR").or(default_directory(var("XDG_CACHE_HOME"), var("HOME"))),
+			disabled: is_set(var("CLCACHE_DISABLE")),
+			read_only: is_set(var("CLCACHE_READONLY")),
+			max_size: max_size,
+			extra_options: var("CLCACHE_EXTRA_OPTIONS").unwrap_or(String::new()),
+			log: var("CLCACHE_LOG"),
+			signing_key: var("CLCACHE_SIGNING_KEY"),
+		}
+	}
+
+	/// Applies the settings that do not depend on the backend
+	pub fn apply(&self, cache: &mut Cache) {
+		cache.set_disabled(self.disabled);
+		cache.set_read_only(self.read_only);
+		cache.set_extra_options(&self.extra_options);
+
+		if let Some(ref signing_key) = self.signing_key {
+			cache.set_signing_key(Some(signing_key.as_bytes()));
+		}
+	}
+
+	/// Creates a file system cache on the configured directory
+	pub fn create_cache(&self) -> Result<Cache, CacheError> {
+		let directory = match self.directory {
+			None => return Err(CacheError::IoError(Error::new(ErrorKind::NotFound, "No cache directory: Set CLCACHE_DIR"))),
+			Some(ref directory) => directory.clone(),
+		};
+
+		// A disabled cache does not touch the directory
+		let backend = if self.disabled || self.read_only {
+			FileSystemCache::read_only(directory.clone())
+		} else {
+			FileSystemCache::new(directory.clone())
+		};
+
+		let mut cache = match backend {
+			Some(mut backend) => {
+				backend.set_max_size(self.max_size);
+				Cache::new(Box::new(backend))
+			},
+			None if self.disabled => Cache::new(Box::new(Volatile::new())),
+			None => return Err(CacheError::IoError(Error::new(ErrorKind::Other, format!("Could not open cache directory: {}", directory)))),
+		};
+
+		self.apply(&mut cache);
+
+		Ok(cache)
+	}
+
+	/// Sets up the logger with the `CLCACHE_LOG` filter. Only the first call has effect
+	pub fn init_log(&self) {
+		static LOG: Once = ONCE_INIT;
+
+		if let Some(ref filter) = self.log {
+			LOG.call_once(|| {
+				let mut builder = LogBuilder::new();
+				builder.parse(filter);
+
+				if builder.init().is_err() {
+					warn!("CLCACHE_LOG is ignored: A logger is already set up");
+				}
+			});
+		}
+	}
+}
+
+/// Follows the XDG base directory specification: `$XDG_CACHE_HOME/clcache`, falling back to
+/// `~/.cache/clcache` when the variable is not set or it's not an absolute path
+pub fn default_directory(xdg_cache_home: Option<String>, home: Option<String>) -> Option<String> {
+	match xdg_cache_home {
+		Some(ref xdg) if xdg.starts_with("/") => Some(format!("{}/clcache", xdg.trim_right_matches('/'))),
+		_ => home.map(|home| format!("{}/.cache/clcache", home.trim_right_matches('/'))),
+	}
+}
+
+/// Parses sizes like `1048576`, `512K`, `64M` or `2G`
+pub fn parse_size(value: &str) -> Option<u64> {
+	let value = value.trim();
+	let (number, multiplier) = match value.chars().last() {
+		Some('k') | Some('K') => (&value[..value.len() - 1], 1 << 10),
+		Some('m') | Some('M') => (&value[..value.len() - 1], 1 << 20),
+		Some('g') | Some('G') => (&value[..value.len() - 1], 1 << 30),
+		_ => (value, 1),
+	};
+
+	number.trim().parse::<u64>().ok().and_then(|x| x.checked_mul(multiplier))
+}
+
+fn is_set(value: Option<String>) -> bool {
+	match value {
+		None => false,
+		Some(value) => match &value.to_lowercase()[..] {
+			"0" | "false" | "no" | "off" => false,
+			_ => true,
+		},
+	}
+}
+
+/// Builds a cache from the configuration, or returns the error code and a message
+pub fn create_cache(config: &CacheConfig) -> Result<Cache, (ErrorCode, String)> {
+	if config.version != CONFIG_VERSION {
+		return Err((ErrorCode::InvalidArgument, format!("Unsupported config version: {}", config.version)));
+	}
+
+	let read_only = config.read_only != 0;
+	let backend: Box<CacheBackend> = match config.backend {
+		BACKEND_MEMORY => {
+			if read_only {
+				return Err((ErrorCode::InvalidArgument, "Memory caches can not be read-only".to_string()));
+			}
+
+			match config.max_size {
+				0 => Box::new(Volatile::new()),
+				max_size => Box::new(BoundedVolatile::new(max_size as usize)),
+			}
+		},
+		BACKEND_FILESYSTEM => {
+			// Without path, the default directory is used
+			let path = match EnvConfig::from_env().directory {
+				Some(ref directory) if config.path.is_null() => directory.clone(),
+				_ => try!{path_from_config(config)},
+			};
+
+			let cache = match (read_only, config.sharded != 0) {
+				(true, _) => FileSystemCache::read_only(path.clone()),
+				(false, true) => FileSystemCache::with_layout(path.clone(), Layout::sharded()),
+				(false, false) => FileSystemCache::new(path.clone()),
+			};
+
+			match cache {
+				None => return Err(could_not_create(&path)),
+				Some(mut cache) => {
+					if config.max_size > 0 {
+						cache.set_max_size(Some(config.max_size));
+					}
+
+					Box::new(cache)
+				},
+			}
+		},
+		BACKEND_PACK => {
+			let path = try!{path_from_config(config)};
+			try!{check_no_limit(config)};
+
+			let pack = if read_only {
+				PackBackend::open_read_only(path.clone())
+			} else {
+				PackBackend::open(path.clone())
+			};
+
+			match pack {
+				Err(_) => return Err(could_not_create(&path)),
+				Ok(pack) => Box::new(pack),
+			}
+		},
+		BACKEND_SQLITE => {
+			let path = try!{path_from_config(config)};
+
+			let db = if read_only {
+				SqliteBackend::open_read_only(&path)
+			} else {
+				SqliteBackend::open(&path)
+			};
+
+			match db {
+				Err(_) => return Err(could_not_create(&path)),
+				Ok(mut db) => {
+					if config.max_size > 0 {
+						db.set_max_size(Some(config.max_size));
+					}
+
+					Box::new(db)
+				},
+			}
+		},
+		other => return Err((ErrorCode::InvalidArgument, format!("Unknown backend: {}", other))),
+	};
+
+	Ok(Cache::new(backend))
+}
+
+fn path_from_config(config: &CacheConfig) -> Result<String, (ErrorCode, String)> {
+	if config.path.is_null() {
+		return Err((ErrorCode::InvalidArgument, "The path of the cache can not be NULL".to_string()));
+	}
+
+	match unsafe { CStr::from_ptr(config.path) }.to_str() {
+		Ok(path) => Ok(path.to_string()),
+		Err(_) => Err((ErrorCode::InvalidArgument, "The path of the cache is not valid UTF-8".to_string())),
+	}
+}
+
+fn check_no_limit(config: &CacheConfig) -> Result<(), (ErrorCode, String)> {
+	match config.max_size {
+		0 => Ok(()),
+		_ => Err((ErrorCode::InvalidArgument, "Size limits are not supported by pack caches".to_string())),
+	}
+}
+
+fn could_not_create(path: &String) -> (ErrorCode, String) {
+	(ErrorCode::CouldNotCreateCache, format!("Could not create a cache on: {}", path))
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use error::ErrorCode;
+	use std::env;
+	use std::ffi::CString;
+	use std::fs::remove_dir_all;
+	use std::process;
+	use std::ptr;
+
+	fn config(backend: u32) -> CacheConfig {
+		CacheConfig {
+			version: CONFIG_VERSION,
+			backend: backend,
+			path: ptr::null(),
+			read_only: 0,
+			max_size: 0,
+			sharded: 0,
+		}
+	}
+
+	#[test]
+	fn it_reads_the_settings_from_the_environment() {
+		let config = EnvConfig::from_vars(|name| match name {
+			"HOME" => Some("/home/user".to_string()),
+			"CLCACHE_READONLY" => Some("1".to_string()),
+			"CLCACHE_DISABLE" => Some("0".to_string()),
+			"CLCACHE_MAX_SIZE" => Some("64M".to_string()),
+			"CLCACHE_EXTRA_OPTIONS" => Some("-cl-fast-relaxed-math".to_string()),
+			"CLCACHE_SIGNING_KEY" => Some("secret".to_string()),
+			_ => None,
+		});
+
+		assert_eq!(config.directory, Some("/home/user/.cache/clcache".to_string()));
+		assert!(config.read_only);
+		assert!(!config.disabled);
+		assert_eq!(config.max_size, Some(64 * 1024 * 1024));
+		assert_eq!(config.extra_options, "-cl-fast-relaxed-math");
+		assert_eq!(config.signing_key, Some("secret".to_string()));
+	}
+
+	#[test]
+	fn it_follows_the_xdg_cache_directory() {
+		assert_eq!(default_directory(Some("/var/cache/".to_string()), Some("/home/user".to_string())), Some("/var/cache/clcache".to_string()));
+		assert_eq!(default_directory(Some("relative".to_string()), Some("/home/user".to_string())), Some("/home/user/.cache/clcache".to_string()));
+		assert_eq!(default_directory(None, None), None);
+		assert_eq!(parse_size("12k"), Some(12 * 1024));
+		assert_eq!(parse_size("big"), None);
+		assert_eq!(parse_size("18446744073709551615G"), None);
+	}
+
+	#[test]
+	fn it_rejects_unknown_versions_and_backends() {
+		let mut unknown_version = config(BACKEND_MEMORY);
+		unknown_version.version = CONFIG_VERSION + 1;
+
+		match create_cache(&unknown_version) {
+			Err((ErrorCode::InvalidArgument, _)) => (),
+			_ => panic!("Unknown config versions should be rejected"),
+		}
+		match create_cache(&config(42)) {
+			Err((ErrorCode::InvalidArgument, _)) => (),
+			_ => panic!("Unknown backends should be rejected"),
+		}
+	}
+
+	#[test]
+	fn it_requires_a_path_for_persistent_backends() {
+		assert!(create_cache(&config(BACKEND_MEMORY)).is_ok());
+		assert!(create_cache(&config(BACKEND_PACK)).is_err());
+
+		let path = CString::new(":memory:").unwrap();
+		let mut sqlite = config(BACKEND_SQLITE);
+		sqlite.path = path.as_ptr();
+		sqlite.max_size = 1024;
+
+		assert!(create_cache(&sqlite).is_ok());
+	}
+
+	#[test]
+	fn it_limits_the_size_of_file_system_caches() {
+		let dir = env::temp_dir().join(format!("clcache_config_limit_{}", process::id()));
+		let _ = remove_dir_all(&dir);
+		let path = CString::new(dir.to_str().unwrap()).unwrap();
+		let mut disk = config(BACKEND_FILESYSTEM);
+		disk.path = path.as_ptr();
+		disk.max_size = 1024;
+
+		assert!(create_cache(&disk).is_ok());
+
+		let mut pack = config(BACKEND_PACK);
+		pack.path = path.as_ptr();
+		pack.max_size = 1024;
+
+		match create_cache(&pack) {
+			Err((ErrorCode::InvalidArgument, _)) => (),
+			_ => panic!("Pack caches should reject the size limits"),
+		}
+
+		remove_dir_all(&dir).unwrap();
+	}
+}
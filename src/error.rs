@@ -0,0 +1,98 @@
+use cache::CacheError;
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::ffi::CString;
+use opencl::cl::*;
+
+/// Error codes returned through the C API. The values are part of the API, so they
+/// should never be changed; new codes should be added at the end.
+#[repr(C)]
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum ErrorCode {
+	Success = 0,
+	InvalidCache = 1,
+	InvalidArgument = 2,
+	BuildError = 3,
+	OpenClError = 4,
+	NotAllBinariesLoaded = 5,
+	NeedBinaryProgram = 6,
+	BackendError = 7,
+	ReadOnly = 8,
+	IoError = 9,
+	CouldNotCreateCache = 10,
+}
+
+impl<'a> From<&'a CacheError> for ErrorCode {
+	fn from(error: &'a CacheError) -> ErrorCode {
+		match *error {
+			CacheError::ClBuildError(_) => ErrorCode::BuildError,
+			CacheError::ClError(_) => ErrorCode::OpenClError,
+			CacheError::NotAllBinariesLoaded(_) => ErrorCode::NotAllBinariesLoaded,
+			CacheError::NeedBinaryProgram(_) => ErrorCode::NeedBinaryProgram,
+			CacheError::CacheError => ErrorCode::BackendError,
+			CacheError::ReadOnly => ErrorCode::ReadOnly,
+			CacheError::IoError(_) => ErrorCode::IoError,
+		}
+	}
+}
+
+/// Last error that happened on the current thread
+pub struct LastError {
+	pub code: ErrorCode,
+	pub message: CString,
+	pub build_logs: HashMap<usize, CString>,
+}
+
+impl LastError {
+	fn success() -> LastError {
+		LastError {
+			code: ErrorCode::Success,
+			message: CString::new("").unwrap(),
+			build_logs: HashMap::new(),
+		}
+	}
+}
+
+thread_local!(static LAST_ERROR: RefCell<LastError> = RefCell::new(LastError::success()));
+
+pub fn clear_last_error() {
+	LAST_ERROR.with(|last_error| *last_error.borrow_mut() = LastError::success());
+}
+
+pub fn set_last_error(code: ErrorCode, message: &str) {
+	// Messages with inner NUL characters can not be returned to C
+	let message = CString::new(message.replace("\0", "")).unwrap();
+
+	LAST_ERROR.with(|last_error| {
+		*last_error.borrow_mut() = LastError {
+			code: code,
+			message: message,
+			build_logs: HashMap::new(),
+		}
+	});
+}
+
+pub fn set_last_cache_error(error: &CacheError) {
+	set_last_error(ErrorCode::from(error), &format!("{}", error));
+
+	if let CacheError::ClBuildError(ref logs) = *error {
+		LAST_ERROR.with(|last_error| {
+			let ref mut build_logs = last_error.borrow_mut().build_logs;
+
+			for (device, log) in logs {
+				let log = CString::new(log.replace("\0", "")).unwrap();
+				build_logs.insert(device.get_id() as usize, log);
+			}
+		});
+	}
+}
+
+/// Runs the closure with the last error of the current thread. Pointers to the message
+/// or the logs are valid until the next call to the library from the same thread.
+pub fn with_last_error<F, T>(f: F) -> T where F: FnOnce(&LastError) -> T {
+	LAST_ERROR.with(|last_error| f(&*last_error.borrow()))
+}
+
+pub fn build_log_key(device: cl_device_id) -> usize {
+	device as usize
+}
@@ -4,58 +4,237 @@ extern crate opencl;
 extern crate crypto;
 extern crate libc;
 extern crate regex;
+extern crate rusqlite;
+extern crate flate2;
+extern crate yaml_rust;
+extern crate env_logger;
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate lazy_static;
 
 pub mod cache;
 pub mod cl;
+pub mod error;
+pub mod config;
+pub mod build;
 
-use cache::{Cache, CacheError};
+use cache::{Cache, CacheError, CacheStats};
 use cache::disk::FileSystemCache;
-use std::cell::RefCell;
+use cache::callback::*;
+use cache::volatile::{BoundedVolatile, Volatile};
+use cache::binary::Binary;
+use cache::embedded::{EmbeddedBackend, RawEmbeddedEntry};
+use config::*;
+use error::*;
+use std::cell::{Cell, RefCell};
 use std::ffi::CStr;
 use std::rc::Rc;
 use opencl::cl::*;
+use opencl::cl::ll;
+use opencl::cl::CLStatus;
 use cl::device::Device;
 use cl::program::Program;
 use cl::context::Context;
 use std::sync::Arc;
 use std::ptr;
+use std::mem;
 
 thread_local!(static CACHE_CONT: RefCell<CacheContainer> = RefCell::new(CacheContainer::new()));
+// Cache used by `cl_cache_build_program` on the current thread
+thread_local!(static BUILD_CACHE: Cell<Option<i32>> = Cell::new(None));
+// Whether `cl_cache_build_program` registers substitutes on the hits of the current thread
+thread_local!(static BUILD_SUBSTITUTES: Cell<bool> = Cell::new(false));
+
+pub type BuildNotify = Option<extern "C" fn(cl_program, *mut libc::c_void)>;
+
+// Handles are built with the slot index on the lower 16 bits and the slot generation
+// on the upper ones. Each time a cache is destroyed, the generation of its slot is
+// increased, so a stale handle will not reach the cache that reuses the slot.
+const SLOT_BITS: u32 = 16;
+const SLOT_MASK: u32 = (1 << SLOT_BITS) - 1;
+const GENERATION_MASK: u16 = 0x7FFF;
+
+struct Slot {
+	generation: u16,
+	cache: Option<Arc<RefCell<Cache>>>,
+}
 
 struct CacheContainer {
-	caches: Vec<Arc<RefCell<Cache>>>,
+	slots: Vec<Slot>,
+	free: Vec<usize>,
 }
 
 impl CacheContainer {
 	pub fn new() -> CacheContainer {
 		CacheContainer {
-			caches: Vec::new(),
+			slots: Vec::new(),
+			free: Vec::new(),
 		}
 	}
 
-	pub fn put(&mut self, cache: Arc<RefCell<Cache>>) -> u32 {
-		self.caches.push(cache.clone());
+	pub fn put(&mut self, cache: Arc<RefCell<Cache>>) -> Option<u32> {
+		let index = match self.free.pop() {
+			Some(index) => index,
+			None => {
+				if self.slots.len() > SLOT_MASK as usize {
+					return None;
+				}
 
-		self.caches.len() as u32 - 1
+				self.slots.push(Slot { generation: 0, cache: None });
+				self.slots.len() - 1
+			},
+		};
+
+		self.slots[index].cache = Some(cache);
+
+		Some(((self.slots[index].generation as u32) << SLOT_BITS) | index as u32)
 	}
 
-	pub fn get(&self, index: u32) -> Option<Arc<RefCell<Cache>>> {
-		if (index as usize) < self.caches.len() {
-			Some(self.caches[index as usize].clone())
-		} else {
-			None
+	pub fn get(&self, handle: u32) -> Option<Arc<RefCell<Cache>>> {
+		self.slot(handle).and_then(|index| self.slots[index].cache.clone())
+	}
+
+	pub fn remove(&mut self, handle: u32) -> bool {
+		let index = match self.slot(handle) {
+			None => return false,
+			Some(index) => index,
+		};
+
+		let ref mut slot = self.slots[index];
+		if slot.cache.take().is_none() {
+			return false;
+		}
+
+		slot.generation = slot.generation.wrapping_add(1) & GENERATION_MASK;
+		self.free.push(index);
+
+		true
+	}
+
+	/// Drops all the caches. The slots are kept, so the handles created before are still detected as stale
+	pub fn clear(&mut self) {
+		for index in 0..self.slots.len() {
+			let handle = ((self.slots[index].generation as u32) << SLOT_BITS) | index as u32;
+			self.remove(handle);
+		}
+	}
+
+	fn slot(&self, handle: u32) -> Option<usize> {
+		let index = (handle & SLOT_MASK) as usize;
+		let generation = (handle >> SLOT_BITS) as u16;
+
+		match self.slots.get(index) {
+			Some(slot) if slot.generation == generation => Some(index),
+			_ => None,
 		}
 	}
 }
 
+/// Returns the version of the C API implemented by the library, to be checked
+/// against the `CL_CACHE_API_VERSION` of the header used to build the host
+#[no_mangle]
+pub extern "C" fn cl_cache_api_version() -> u32 {
+	API_VERSION
+}
+
+#[no_mangle]
+pub extern "C" fn cl_cache_create(config: *const CacheConfig) -> i32 {
+	clear_last_error();
+
+	if config.is_null() {
+		set_last_error(ErrorCode::InvalidArgument, "Argument 'config' can not be NULL");
+		return -1;
+	}
+
+	match create_cache(unsafe { &*config }) {
+		Err((code, message)) => {
+			set_last_error(code, &message);
+			-1
+		},
+		Ok(cache) => add_cache(RefCell::new(cache)),
+	}
+}
+
+/// Creates a file system cache configured with the `CLCACHE_*` environment variables. Without
+/// `CLCACHE_DIR`, the cache is created on the XDG cache directory (`~/.cache/clcache`)
+#[no_mangle]
+pub extern "C" fn cl_cache_create_from_env() -> i32 {
+	clear_last_error();
+
+	match EnvConfig::from_env().create_cache() {
+		Err(error) => {
+			set_last_error(ErrorCode::CouldNotCreateCache, &format!("{}", error));
+			-1
+		},
+		Ok(cache) => add_cache(RefCell::new(cache)),
+	}
+}
+
+/// Creates an in-memory cache. With a `max_size` of 0 the cache has no size limit
+#[no_mangle]
+pub extern "C" fn cl_cache_create_memory(max_size: u64) -> i32 {
+	clear_last_error();
+
+	let cache = match max_size {
+		0 => Cache::new(Box::new(Volatile::new())),
+		max_size => Cache::new(Box::new(BoundedVolatile::new(max_size as usize))),
+	};
+
+	add_cache(RefCell::new(cache))
+}
+
+/// Creates a read-only cache that serves the `count` entries of `entries`, usually the static
+/// array of a header generated by the embed command. The binaries are not copied, so they
+/// have to live as long as the cache
+#[no_mangle]
+pub extern "C" fn cl_cache_create_embedded(entries: *const RawEmbeddedEntry, count: libc::size_t) -> i32 {
+	clear_last_error();
+
+	if entries.is_null() && count > 0 {
+		set_last_error(ErrorCode::InvalidArgument, "Argument 'entries' can not be NULL");
+		return -1;
+	}
+
+	let raw_entries = if count > 0 {
+		unsafe { std::slice::from_raw_parts(entries, count as usize) }
+	} else {
+		&[]
+	};
+
+	let mut binaries = Vec::new();
+	for entry in raw_entries {
+		let key = match str_from_c(entry.key, "key") {
+			None => return -1,
+			Some(key) => key,
+		};
+
+		if entry.binary.is_null() {
+			set_last_error(ErrorCode::InvalidArgument, "Argument 'binary' can not be NULL");
+			return -1;
+		}
+
+		// The caller guarantees that the binaries outlive the cache
+		let binary: &'static [u8] = unsafe { std::slice::from_raw_parts(entry.binary, entry.size as usize) };
+		binaries.push((key.to_string(), Binary::from_static(binary)));
+	}
+
+	add_cache(RefCell::new(Cache::new(Box::new(EmbeddedBackend::from_binaries(binaries)))))
+}
+
 #[no_mangle]
 pub extern "C" fn cl_cache_create_fs(path: *const libc::c_char) -> i32 {
+	clear_last_error();
+
 	// Put a mutex here
-	let path = unsafe {CStr::from_ptr(path).to_string_lossy().into_owned()};
+	let path = match str_from_c(path, "path") {
+		None => return -1,
+		Some(path) => path,
+	};
+
 	let fs_cache = FileSystemCache::new(path.to_string());
 	if fs_cache.is_none() {
+		set_last_error(ErrorCode::CouldNotCreateCache, &format!("Could not create a file system cache on: {}", path));
 		return -1;
 	}
 
@@ -65,6 +244,31 @@ pub extern "C" fn cl_cache_create_fs(path: *const libc::c_char) -> i32 {
 	add_cache(RefCell::new(cache))
 }
 
+#[no_mangle]
+pub extern "C" fn cl_cache_create_custom(
+	get: Option<GetCallback>,
+	put: Option<PutCallback>,
+	delete: Option<DeleteCallback>,
+	release: Option<ReleaseCallback>,
+	user_data: *mut libc::c_void
+) -> i32
+{
+	clear_last_error();
+
+	let (get, put) = match (get, put) {
+		(Some(get), Some(put)) => (get, put),
+		_ => {
+			set_last_error(ErrorCode::InvalidArgument, "Get and put callbacks are mandatory");
+			return -1;
+		},
+	};
+
+	let backend = Box::new(CallbackBackend::new(get, put, delete, release, user_data));
+	let cache = Cache::new(backend);
+
+	add_cache(RefCell::new(cache))
+}
+
 #[no_mangle]
 pub extern "C" fn cl_cache_get(
 	cache_id: i32,
@@ -74,20 +278,23 @@ pub extern "C" fn cl_cache_get(
 	context: *const libc::c_void
 ) -> *mut cl_program
 {
-	let source_str = unsafe{ CStr::from_ptr(source).to_str() };
-	if source_str.is_err() {
-		return ptr::null_mut();
-	}
+	clear_last_error();
+
+	let source_cstr = match str_from_c(source, "source") {
+		None => return ptr::null_mut(),
+		Some(source) => source,
+	};
 
-	let source_cstr = source_str.unwrap();
-	
 	let cache_result = get_cache(cache_id as usize);
 	if cache_result.is_none() {
 		return ptr::null_mut();
 	}
 	let cache = cache_result.unwrap();
 
-	let devices_vec = get_devices_vector(num_devices, devices);
+	let devices_vec = match get_devices_vector(num_devices as usize, devices) {
+		None => return ptr::null_mut(),
+		Some(devices) => devices,
+	};
 	let context = Context::from_id(context as cl_context);
 
  	let get_result = cache.borrow_mut().get(
@@ -108,19 +315,23 @@ pub extern "C" fn cl_cache_get_with_tag(
 	context: *const libc::c_void
 ) -> *mut cl_program
 {
-	let tag_str = unsafe{ CStr::from_ptr(tag).to_str() };
-	if tag_str.is_err() {
-		return ptr::null_mut();
-	}
+	clear_last_error();
+
+	let tag_cstr = match str_from_c(tag, "tag") {
+		None => return ptr::null_mut(),
+		Some(tag) => tag,
+	};
 
-	let tag_cstr = tag_str.unwrap();
 	let cache_result = get_cache(cache_id as usize);
 	if cache_result.is_none() {
 		return ptr::null_mut();
 	}
 
 	let cache = cache_result.unwrap();
-	let devices_vec = get_devices_vector(num_devices, devices);
+	let devices_vec = match get_devices_vector(num_devices as usize, devices) {
+		None => return ptr::null_mut(),
+		Some(devices) => devices,
+	};
 	let context = Context::from_id(context as cl_context);
 
  	let get_result = cache.borrow_mut().get_with_tag(
@@ -132,6 +343,48 @@ pub extern "C" fn cl_cache_get_with_tag(
  	return_from_program_result(get_result)
 }
 
+#[no_mangle]
+pub extern "C" fn cl_cache_get_with_tag_and_options(
+	cache_id: i32,
+	tag: *const libc::c_char,
+	num_devices: u8,
+	devices: *const libc::c_void,
+	context: *const libc::c_void,
+	options: *const libc::c_char
+) -> *mut cl_program
+{
+	clear_last_error();
+
+	let tag_cstr = match str_from_c(tag, "tag") {
+		None => return ptr::null_mut(),
+		Some(tag) => tag,
+	};
+
+	let options_cstr = match str_from_c(options, "options") {
+		None => return ptr::null_mut(),
+		Some(options) => options,
+	};
+
+	if let Some(cache) = get_cache(cache_id as usize) {
+		let context = Context::from_id(context as cl_context);
+		let devices_vec = match get_devices_vector(num_devices as usize, devices) {
+			None => return ptr::null_mut(),
+			Some(devices) => devices,
+		};
+
+		let get_result = cache.borrow_mut().get_with_tag_and_options(
+			&tag_cstr,
+			&devices_vec,
+			&context,
+			&options_cstr
+		);
+
+		return_from_program_result(get_result)
+	} else {
+		ptr::null_mut()
+	}
+}
+
 #[no_mangle]
 pub extern "C" fn cl_cache_put_with_tag(
 	cache_id: i32,
@@ -141,33 +394,39 @@ pub extern "C" fn cl_cache_put_with_tag(
 	program: *const libc::c_void
 ) -> i32
 {
-	let tag_str = unsafe{ CStr::from_ptr(tag).to_str() };
-	if tag_str.is_err() {
-		return 0;
-	}
+	clear_last_error();
 
-	let tag_cstr = tag_str.unwrap();
-	
-	let cache_result = get_cache(cache_id as usize);
-	if cache_result.is_none() {
-		return 0;
-	}
-	let cache = cache_result.unwrap();
+	let tag_cstr = match str_from_c(tag, "tag") {
+		None => return 0,
+		Some(tag) => tag,
+	};
 
-	let devices_vec = get_devices_vector(num_devices, devices);
-	let program = Program::from_cl_program(program as cl_program);
+	put_with_tag(cache_id, tag_cstr, num_devices, devices, program, "")
+}
 
- 	let put_result = cache.borrow_mut().put_with_tag(
- 		&tag_cstr,
- 		&devices_vec,
- 		&program
- 	);
+#[no_mangle]
+pub extern "C" fn cl_cache_put_with_tag_and_options(
+	cache_id: i32,
+	tag: *const libc::c_char,
+	num_devices: u8,
+	devices: *const libc::c_void,
+	program: *const libc::c_void,
+	options: *const libc::c_char
+) -> i32
+{
+	clear_last_error();
+
+	let tag_cstr = match str_from_c(tag, "tag") {
+		None => return 0,
+		Some(tag) => tag,
+	};
 
- 	if put_result.is_err() {
- 		0
- 	} else { 	
- 		1
- 	}
+	let options_cstr = match str_from_c(options, "options") {
+		None => return 0,
+		Some(options) => options,
+	};
+
+	put_with_tag(cache_id, tag_cstr, num_devices, devices, program, options_cstr)
 }
 
 #[no_mangle]
@@ -180,21 +439,24 @@ pub extern "C" fn cl_cache_get_with_options(
 	options: *const libc::c_char
 ) -> *mut cl_program
 {
-	let source_str = unsafe{ CStr::from_ptr(source).to_str() };
-	if source_str.is_err() {
-		return ptr::null_mut();
-	}
-	let source_cstr = source_str.unwrap();
+	clear_last_error();
 
-	let option_str = unsafe{ CStr::from_ptr(options).to_str() };
-	if option_str.is_err() {
-		return ptr::null_mut();
-	}
-	let options_cstr = option_str.unwrap();
+	let source_cstr = match str_from_c(source, "source") {
+		None => return ptr::null_mut(),
+		Some(source) => source,
+	};
+
+	let options_cstr = match str_from_c(options, "options") {
+		None => return ptr::null_mut(),
+		Some(options) => options,
+	};
 
 	if let Some(cache) = get_cache(cache_id as usize) {
 		let context = Context::from_id(context as cl_context);
-		let devices_vec = get_devices_vector(num_devices, devices);
+		let devices_vec = match get_devices_vector(num_devices as usize, devices) {
+			None => return ptr::null_mut(),
+			Some(devices) => devices,
+		};
 
 	 	let get_result = cache.borrow_mut().get_with_options(
 	 		&source_cstr,
@@ -209,41 +471,607 @@ pub extern "C" fn cl_cache_get_with_options(
 	}
 }
 
-fn get_devices_vector(num_devices: u8, devices: *const libc::c_void) -> Vec<Rc<Device>> {
-	let devices = unsafe {std::slice::from_raw_parts(devices as *const cl_device_id, std::mem::size_of::<cl_device_id>() * num_devices as usize)};
+/// Removes the binaries of the source built with the options. Returns 1 on success
+#[no_mangle]
+pub extern "C" fn cl_cache_invalidate(
+	cache_id: i32,
+	source: *const libc::c_char,
+	num_devices: u8,
+	devices: *const libc::c_void,
+	options: *const libc::c_char
+) -> i32
+{
+	clear_last_error();
+
+	let source_cstr = match str_from_c(source, "source") {
+		None => return 0,
+		Some(source) => source,
+	};
+
+	let options_cstr = match str_from_c(options, "options") {
+		None => return 0,
+		Some(options) => options,
+	};
+
+	let cache = match get_cache(cache_id as usize) {
+		None => return 0,
+		Some(cache) => cache,
+	};
+
+	let devices_vec = match get_devices_vector(num_devices as usize, devices) {
+		None => return 0,
+		Some(devices) => devices,
+	};
+	let result = cache.borrow_mut().invalidate(&source_cstr, &devices_vec, &options_cstr);
+
+	return_from_unit_result(result)
+}
+
+/// Removes the binaries stored with the tag and options. Returns 1 on success
+#[no_mangle]
+pub extern "C" fn cl_cache_invalidate_tag(
+	cache_id: i32,
+	tag: *const libc::c_char,
+	num_devices: u8,
+	devices: *const libc::c_void,
+	options: *const libc::c_char
+) -> i32
+{
+	clear_last_error();
+
+	let tag_cstr = match str_from_c(tag, "tag") {
+		None => return 0,
+		Some(tag) => tag,
+	};
+
+	let options_cstr = match str_from_c(options, "options") {
+		None => return 0,
+		Some(options) => options,
+	};
+
+	let cache = match get_cache(cache_id as usize) {
+		None => return 0,
+		Some(cache) => cache,
+	};
+
+	let devices_vec = match get_devices_vector(num_devices as usize, devices) {
+		None => return 0,
+		Some(devices) => devices,
+	};
+	let result = cache.borrow_mut().invalidate_tag(&tag_cstr, &devices_vec, &options_cstr);
+
+	return_from_unit_result(result)
+}
+
+/// Copies the counters of the cache to `stats`. Returns 1 on success
+#[no_mangle]
+pub extern "C" fn cl_cache_get_stats(cache_id: i32, stats: *mut CacheStats) -> i32 {
+	clear_last_error();
+
+	if stats.is_null() {
+		set_last_error(ErrorCode::InvalidArgument, "Argument 'stats' can not be NULL");
+		return 0;
+	}
+
+	match get_cache(cache_id as usize) {
+		None => 0,
+		Some(cache) => {
+			unsafe { *stats = cache.borrow().stats() };
+			1
+		},
+	}
+}
+
+/// Signs the entries of the cache with the `size` bytes of `secret` (see `Cache::set_signing_key`).
+/// A NULL secret disables the signatures. Returns 1 on success
+#[no_mangle]
+pub extern "C" fn cl_cache_set_signing_key(cache_id: i32, secret: *const u8, size: libc::size_t) -> i32 {
+	clear_last_error();
+
+	if !secret.is_null() && size == 0 {
+		set_last_error(ErrorCode::InvalidArgument, "Argument 'secret' can not be empty");
+		return 0;
+	}
+
+	match get_cache(cache_id as usize) {
+		None => 0,
+		Some(cache) => {
+			let secret = if secret.is_null() {
+				None
+			} else {
+				Some(unsafe { std::slice::from_raw_parts(secret, size as usize) })
+			};
+
+			cache.borrow_mut().set_signing_key(secret);
+			1
+		},
+	}
+}
+
+/// Releases the programs kept by the cache for repeated requests (see `Cache::clear_programs`), and
+/// with them their references to the contexts. Returns 1 on success
+#[no_mangle]
+pub extern "C" fn cl_cache_clear_programs(cache_id: i32) -> i32 {
+	clear_last_error();
+
+	match get_cache(cache_id as usize) {
+		None => 0,
+		Some(cache) => {
+			cache.borrow_mut().clear_programs();
+			1
+		},
+	}
+}
+
+/// Same as `cl_cache_get_with_options`, but the source is received like on `clCreateProgramWithSource`.
+/// `options` can be NULL
+#[no_mangle]
+pub extern "C" fn cl_cache_get_with_sources(
+	cache_id: i32,
+	count: cl_uint,
+	strings: *const *const libc::c_char,
+	lengths: *const libc::size_t,
+	num_devices: cl_uint,
+	devices: *const cl_device_id,
+	context: cl_context,
+	options: *const libc::c_char
+) -> cl_program
+{
+	clear_last_error();
+
+	let sources = match sources_from_c(count, strings, lengths) {
+		None => return ptr::null_mut(),
+		Some(sources) => sources,
+	};
+
+	let options_cstr = if options.is_null() {
+		""
+	} else {
+		match str_from_c(options, "options") {
+			None => return ptr::null_mut(),
+			Some(options) => options,
+		}
+	};
+
+	let cache = match get_cache(cache_id as usize) {
+		None => return ptr::null_mut(),
+		Some(cache) => cache,
+	};
+
+	let context = Context::from_id(context);
+	let devices_vec = match get_devices_vector(num_devices as usize, devices as *const libc::c_void) {
+		None => return ptr::null_mut(),
+		Some(devices) => devices,
+	};
+
+	let get_result = cache.borrow_mut().get_with_sources(
+		&sources,
+		&devices_vec,
+		&context,
+		&options_cstr
+	);
+
+	return_from_program_result(get_result) as cl_program
+}
+
+/// Selects the cache used by `cl_cache_build_program` on the current thread. With -1, programs
+/// are built without cache. Returns 1 on success
+#[no_mangle]
+pub extern "C" fn cl_cache_set_build_cache(cache_id: i32) -> i32 {
+	clear_last_error();
+
+	if cache_id != -1 && get_cache(cache_id as usize).is_none() {
+		return 0;
+	}
+
+	BUILD_CACHE.with(|build_cache| build_cache.set(if cache_id == -1 { None } else { Some(cache_id) }));
+
+	1
+}
+
+/// Enables the substitutes on the hits of `cl_cache_build_program` on the current thread. The
+/// source program is not built on a hit, so it can only be used through `cl_cache_program`.
+/// Returns 1 on success
+#[no_mangle]
+pub extern "C" fn cl_cache_set_program_substitutes(enabled: i32) -> i32 {
+	clear_last_error();
+	BUILD_SUBSTITUTES.with(|substitutes| substitutes.set(enabled != 0));
+
+	1
+}
+
+/// Builds a program created from source, with the signature of `clBuildProgram`. Without substitutes,
+/// `program` is always built (a hit does not save the compilation) and its binaries are stored if they
+/// were not on the cache. It only saves the compilation with substitutes enabled: a hit
+/// does not build `program`: the program built from the cached binaries has to be used on its
+/// place, and `cl_cache_program` returns it
+#[no_mangle]
+pub extern "C" fn cl_cache_build_program(
+	program: cl_program,
+	num_devices: cl_uint,
+	device_list: *const cl_device_id,
+	options: *const libc::c_char,
+	pfn_notify: BuildNotify,
+	user_data: *mut libc::c_void
+) -> cl_int
+{
+	clear_last_error();
+
+	let cache = match BUILD_CACHE.with(|build_cache| build_cache.get()) {
+		None => None,
+		Some(cache_id) => get_cache(cache_id as usize),
+	};
+
+	let cache = match cache {
+		Some(cache) => cache,
+		None => unsafe {
+			return ll::clBuildProgram(program, num_devices, device_list, options, mem::transmute(pfn_notify), user_data);
+		},
+	};
+
+	let options_cstr = if options.is_null() {
+		""
+	} else {
+		match str_from_c(options, "options") {
+			None => return CLStatus::CL_INVALID_VALUE as cl_int,
+			Some(options) => options,
+		}
+	};
+
+	let source_program = Program::from_cl_program(program);
+	let devices_vec = if device_list.is_null() {
+		match source_program.get_devices() {
+			Err(error) => {
+				let status = error.error_code().unwrap_or(CLStatus::CL_INVALID_PROGRAM as i32);
+				set_last_cache_error(&CacheError::ClError(error));
+				return status as cl_int;
+			},
+			Ok(devices) => devices,
+		}
+	} else {
+		match get_devices_vector(num_devices as usize, device_list as *const libc::c_void) {
+			None => return CLStatus::CL_INVALID_VALUE as cl_int,
+			Some(devices) => devices,
+		}
+	};
+
+	let substitutes = BUILD_SUBSTITUTES.with(|substitutes| substitutes.get());
+	let result = build::build_program(&mut cache.borrow_mut(), &source_program, &devices_vec, &options_cstr, substitutes);
+	let status = match result {
+		Ok(_) => CLStatus::CL_SUCCESS as cl_int,
+		Err(error) => {
+			set_last_cache_error(&error);
+
+			match error {
+				CacheError::ClError(ref error) => error.error_code().unwrap_or(CLStatus::CL_BUILD_PROGRAM_FAILURE as i32) as cl_int,
+				_ => CLStatus::CL_BUILD_PROGRAM_FAILURE as cl_int,
+			}
+		},
+	};
+
+	// As the build is synchronous, the callback is called before returning
+	if let Some(notify) = pfn_notify {
+		notify(program, user_data);
+	}
+
+	status
+}
+
+/// Returns the program that has to be used on place of a program built with `cl_cache_build_program`
+/// (to create kernels or query the build). For any other program, it returns the same program
+#[no_mangle]
+pub extern "C" fn cl_cache_program(program: cl_program) -> cl_program {
+	build::resolve(program)
+}
+
+/// Same as `clRetainProgram`. The references of the programs built with substitutes have to be
+/// retained and released through the library, so the substitute lives as long as the program
+#[no_mangle]
+pub extern "C" fn cl_cache_retain_program(program: cl_program) -> cl_int {
+	build::retain_program(program)
+}
+
+/// Same as `clReleaseProgram`, but it also releases the program used on its place once the
+/// last reference is released
+#[no_mangle]
+pub extern "C" fn cl_cache_release_program(program: cl_program) -> cl_int {
+	build::release_program(program)
+}
+
+/// Destroys the cache. The handle (and any copy of it) will not be valid anymore
+#[no_mangle]
+pub extern "C" fn cl_cache_destroy(cache_id: i32) -> i32 {
+	clear_last_error();
+
+	let removed = CACHE_CONT.with(|ref_caches| ref_caches.borrow_mut().remove(cache_id as u32));
+	if !removed {
+		set_last_error(ErrorCode::InvalidCache, &format!("Unknown cache id: {}", cache_id));
+		return 0;
+	}
+
+	1
+}
+
+/// Destroys all the caches created on the current thread
+#[no_mangle]
+pub extern "C" fn cl_cache_shutdown() {
+	CACHE_CONT.with(|ref_caches| ref_caches.borrow_mut().clear());
+	clear_last_error();
+}
+
+/// Returns the error code of the last failed call on the current thread
+#[no_mangle]
+pub extern "C" fn cl_cache_last_error() -> i32 {
+	with_last_error(|error| error.code as i32)
+}
+
+/// Returns a description of the last error on the current thread. The string is owned by
+/// the library and it's valid until the next call to the library on the same thread
+#[no_mangle]
+pub extern "C" fn cl_cache_last_error_message() -> *const libc::c_char {
+	with_last_error(|error| error.message.as_ptr())
+}
+
+/// Returns the build log of the given device if the last error was a build error, or NULL
+#[no_mangle]
+pub extern "C" fn cl_cache_last_build_log(device: *const libc::c_void) -> *const libc::c_char {
+	with_last_error(|error| {
+		match error.build_logs.get(&build_log_key(device as cl_device_id)) {
+			None => ptr::null(),
+			Some(log) => log.as_ptr(),
+		}
+	})
+}
+
+fn str_from_c<'a>(value: *const libc::c_char, name: &str) -> Option<&'a str> {
+	if value.is_null() {
+		set_last_error(ErrorCode::InvalidArgument, &format!("Argument '{}' can not be NULL", name));
+		return None;
+	}
+
+	match unsafe{ CStr::from_ptr(value).to_str() } {
+		Ok(value) => Some(value),
+		Err(_) => {
+			set_last_error(ErrorCode::InvalidArgument, &format!("Argument '{}' is not valid UTF-8", name));
+			None
+		},
+	}
+}
+
+/// Reads the `num_devices` devices of the array. The array can only be NULL without devices
+fn get_devices_vector(num_devices: usize, devices: *const libc::c_void) -> Option<Vec<Rc<Device>>> {
+	if num_devices == 0 {
+		return Some(Vec::new());
+	}
+
+	if devices.is_null() {
+		set_last_error(ErrorCode::InvalidArgument, "Argument 'devices' can not be NULL");
+		return None;
+	}
+
+	let devices = unsafe {std::slice::from_raw_parts(devices as *const cl_device_id, num_devices)};
 	let mut devices_vec = Vec::new();
 
 	for i in (0..num_devices) {
-		devices_vec.push(Rc::new(Device::from_device_id(devices[i as usize])));
+		devices_vec.push(Rc::new(Device::from_device_id(devices[i])));
+	}
+
+	Some(devices_vec)
+}
+
+/// Reads the sources with the same rules as `clCreateProgramWithSource`: without lengths, or
+/// with a length of 0, the string is NUL terminated. Otherwise, it has exactly that length
+fn sources_from_c<'a>(count: cl_uint, strings: *const *const libc::c_char, lengths: *const libc::size_t) -> Option<Vec<&'a str>> {
+	if count == 0 || strings.is_null() {
+		set_last_error(ErrorCode::InvalidArgument, "At least one source string is needed");
+		return None;
+	}
+
+	let strings = unsafe { std::slice::from_raw_parts(strings, count as usize) };
+	let mut sources = Vec::new();
+
+	for (i, string) in strings.iter().enumerate() {
+		let length = if lengths.is_null() { 0 } else { unsafe { *lengths.offset(i as isize) } };
+
+		let source = if length == 0 {
+			match str_from_c(*string, "strings") {
+				None => return None,
+				Some(source) => source,
+			}
+		} else {
+			if string.is_null() {
+				set_last_error(ErrorCode::InvalidArgument, "Argument 'strings' can not contain NULL");
+				return None;
+			}
+
+			let bytes = unsafe { std::slice::from_raw_parts(*string as *const u8, length as usize) };
+			match std::str::from_utf8(bytes) {
+				Ok(source) => source,
+				Err(_) => {
+					set_last_error(ErrorCode::InvalidArgument, "Argument 'strings' is not valid UTF-8");
+					return None;
+				},
+			}
+		};
+
+		sources.push(source);
 	}
 
-	devices_vec
+	Some(sources)
+}
+
+fn put_with_tag(
+	cache_id: i32,
+	tag: &str,
+	num_devices: u8,
+	devices: *const libc::c_void,
+	program: *const libc::c_void,
+	options: &str
+) -> i32
+{
+	let cache = match get_cache(cache_id as usize) {
+		None => return 0,
+		Some(cache) => cache,
+	};
+
+	let devices_vec = match get_devices_vector(num_devices as usize, devices) {
+		None => return 0,
+		Some(devices) => devices,
+	};
+	let program = Program::from_cl_program(program as cl_program);
+
+	let put_result = cache.borrow_mut().put_with_tag_and_options(
+		&tag,
+		&devices_vec,
+		&program,
+		&options
+	);
+
+	return_from_unit_result(put_result)
+}
+
+fn return_from_unit_result(result: Result<(), CacheError>) -> i32 {
+	match result {
+		Err(error) => {
+			set_last_cache_error(&error);
+			0
+		},
+		Ok(_) => 1,
+	}
 }
 
 fn return_from_program_result(result: Result<Program, CacheError>) -> *mut cl_program {
-	if result.is_err() {
- 		let p: *mut cl_program = ptr::null_mut();
- 		
- 		p
- 	} else { 	
- 		let program = result.unwrap();
+	match result {
+		Err(error) => {
+			set_last_cache_error(&error);
+			ptr::null_mut()
+		},
+		Ok(program) => {
+			// The reference of the program is given to the caller, who releases it with clReleaseProgram
+			let id = program.get_id();
+			mem::forget(program);
 
- 		program.get_id() as *mut *mut libc::c_void
- 	}
+			id as *mut *mut libc::c_void
+		},
+	}
 }
 
 fn add_cache(cache: RefCell<Cache>) -> i32 {
-	CACHE_CONT.with(|ref_caches| {
+	// The environment overrides the settings of all the caches created through the C API
+	let config = EnvConfig::from_env();
+	config.init_log();
+	config.apply(&mut cache.borrow_mut());
+
+	let handle = CACHE_CONT.with(|ref_caches| {
 		let ref mut caches = *ref_caches.borrow_mut();
 
-		caches.put(Arc::new(cache)) as i32
-	})
+		caches.put(Arc::new(cache))
+	});
+
+	match handle {
+		Some(handle) => handle as i32,
+		None => {
+			set_last_error(ErrorCode::CouldNotCreateCache, "Too many caches alive");
+			-1
+		},
+	}
 }
 
 fn get_cache<'a>(index: usize) -> Option<Arc<RefCell<Cache>>> {
-	CACHE_CONT.with(|ref_caches| {
+	let cache = CACHE_CONT.with(|ref_caches| {
 		let ref caches = *ref_caches.borrow();
 
 		caches.get(index as u32)
-	})
+	});
+
+	if cache.is_none() {
+		set_last_error(ErrorCode::InvalidCache, &format!("Unknown cache id: {}", index));
+	}
+
+	cache
+}
+
+#[cfg(test)]
+mod test {
+	use super::*;
+	use cache::Cache;
+	use cache::volatile::Volatile;
+	use cl::cl_root::*;
+	use cl::platform::*;
+	use std::cell::RefCell;
+	use std::ffi::CString;
+	use std::sync::Arc;
+
+	fn volatile_cache() -> Arc<RefCell<Cache>> {
+		Arc::new(RefCell::new(Cache::new(Box::new(Volatile::new()))))
+	}
+
+	#[test]
+	fn it_does_not_reach_a_new_cache_with_a_stale_handle() {
+		let mut container = CacheContainer::new();
+		let first = container.put(volatile_cache()).unwrap();
+		assert!(container.remove(first));
+
+		let second = container.put(volatile_cache()).unwrap();
+
+		assert!(first != second);
+		assert!(container.get(first).is_none());
+		assert!(container.get(second).is_some());
+		assert!(!container.remove(first));
+	}
+
+	#[test]
+	fn it_detects_the_handles_created_before_a_clear() {
+		let mut container = CacheContainer::new();
+		let first = container.put(volatile_cache()).unwrap();
+		container.clear();
+
+		let second = container.put(volatile_cache()).unwrap();
+
+		assert!(first != second);
+		assert!(container.get(first).is_none());
+		assert!(container.get(second).is_some());
+	}
+
+	#[test]
+	fn it_gives_one_reference_of_the_programs_to_the_caller() {
+		let platform = ClRoot::get_platform(&PlatformQuery::Index(0)).unwrap();
+		let devices = platform.get_devices_query(&DeviceQuery::Type(DeviceType::All));
+		let context = Context::from_devices(&devices);
+		let raw_devices: Vec<cl_device_id> = devices.iter().map(|device| device.get_id()).collect();
+		let source = CString::new("__kernel void add(__global long *A) { A[get_global_id(0)] += 1; }").unwrap();
+
+		let cache_id = cl_cache_create_memory(1024 * 1024);
+		for _ in 0..3 {
+			let program = cl_cache_get(
+				cache_id,
+				source.as_ptr(),
+				raw_devices.len() as u8,
+				raw_devices.as_ptr() as *const libc::c_void,
+				context.get_id() as *const libc::c_void
+			) as cl_program;
+			assert!(!program.is_null());
+
+			// The reference of the caller and the one kept by the cache for the repeated requests
+			let references = Program::from_cl_program(program).get_reference_count().unwrap();
+			assert_eq!(references, 3);
+
+			unsafe { ll::clReleaseProgram(program) };
+		}
+
+		cl_cache_destroy(cache_id);
+	}
+
+	#[test]
+	fn it_only_accepts_a_null_devices_array_without_devices() {
+		let cache_id = cl_cache_create_memory(0);
+		let source = CString::new("__kernel void empty() {}").unwrap();
+		let options = CString::new("").unwrap();
+
+		assert_eq!(cl_cache_invalidate(cache_id, source.as_ptr(), 1, ptr::null(), options.as_ptr()), 0);
+		assert_eq!(cl_cache_last_error(), ErrorCode::InvalidArgument as i32);
+		assert_eq!(cl_cache_invalidate(cache_id, source.as_ptr(), 0, ptr::null(), options.as_ptr()), 1);
+
+		cl_cache_destroy(cache_id);
+	}
 }